@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::{env, fs, path::Path};
+use std::{collections::HashMap, env, fs, path::Path};
 
 #[derive(Default, Debug, Serialize, Deserialize)]
 pub struct TimeoutConfig {
@@ -8,6 +8,58 @@ pub struct TimeoutConfig {
     pub critical: u32,
 }
 
+/// Maximum number of popups shown at once, overall and per urgency. 0 means no limit.
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaxVisibleConfig {
+    pub total: u32,
+    pub low: u32,
+    pub normal: u32,
+    pub critical: u32,
+}
+
+/// Sound played for each urgency, either a path or a name from the sound theme. An empty string
+/// means no sound.
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SoundConfig {
+    pub low: String,
+    pub normal: String,
+    pub critical: String,
+}
+
+/// At most `count` popups per app within `interval` seconds. Notifications over the limit are
+/// collapsed into a single summary popup but still go to the history. A count of 0 disables the
+/// limit.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub count: u32,
+    pub interval: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            count: 0,
+            interval: 10,
+        }
+    }
+}
+
+/// Eww variables kept up to date with the state of the daemon, e.g. for a badge in a bar. An
+/// empty name disables the variable, which all of them are by default.
+#[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StateVarsConfig {
+    pub active_count: String,
+    pub history_count: String,
+    pub unread_count: String,
+    /// JSON object mapping app names to their number of active notifications
+    pub app_counts: String,
+    pub dnd: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum NotificationWindow {
@@ -21,6 +73,48 @@ impl Default for NotificationWindow {
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rule {
+    /// Regex matched against the application name
+    pub app_name: Option<String>,
+    /// Regex matched against the summary
+    pub summary: Option<String>,
+    /// Regex matched against the body
+    pub body: Option<String>,
+    /// Urgency to match, one of low, normal or critical
+    pub urgency: Option<String>,
+    /// Category hint to match
+    pub category: Option<String>,
+    /// Desktop entry ID to match, without the .desktop suffix
+    pub desktop_entry: Option<String>,
+    /// Urgency the notification is changed to
+    pub set_urgency: Option<String>,
+    /// Timeout in seconds replacing the one the notification asked for
+    pub timeout: Option<u32>,
+    pub suppress_popup: bool,
+    pub skip_history: bool,
+    pub transient: bool,
+    /// Window the notification is shown in instead of `eww_notification_window`
+    pub window: Option<String>,
+    /// Widget the notification is drawn with instead of `eww_notification_widget`
+    pub widget: Option<String>,
+}
+
+/// Overrides for an application, see `apps` in the config
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    /// Name shown instead of the one the application sent
+    pub name: Option<String>,
+    /// Icon path or icon name used as the application icon
+    pub icon: Option<String>,
+    /// Urgency used when the notification doesn't set one
+    pub urgency: Option<String>,
+    /// Timeout in seconds used when the notification asks for the default one
+    pub timeout: Option<u32>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub eww_binary_path: String,
@@ -41,6 +135,63 @@ pub struct Config {
     pub timeout: TimeoutConfig,
     #[serde(default)]
     pub update_history: bool,
+    #[serde(default = "default_dnd_allow_critical")]
+    pub dnd_allow_critical: bool,
+    #[serde(default)]
+    pub persist_history: bool,
+    #[serde(default)]
+    pub history_file: String,
+    #[serde(default)]
+    pub group_notifications: bool,
+    #[serde(default = "default_eww_group_widget")]
+    pub eww_group_widget: String,
+    #[serde(default)]
+    pub max_visible: MaxVisibleConfig,
+    #[serde(default = "default_eww_overflow_widget")]
+    pub eww_overflow_widget: String,
+    #[serde(default)]
+    pub sound_player: String,
+    #[serde(default = "default_sound_theme")]
+    pub sound_theme: String,
+    #[serde(default)]
+    pub sounds: SoundConfig,
+    #[serde(default = "default_image_cache_size")]
+    pub image_cache_size: u32,
+    #[serde(default = "default_dedup_history_window")]
+    pub dedup_history_window: u32,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub eww_state_vars: StateVarsConfig,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+    /// Overrides by the app name an application sends or its desktop entry ID
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub apps: HashMap<String, AppConfig>,
+}
+
+fn default_dnd_allow_critical() -> bool {
+    true
+}
+
+fn default_eww_group_widget() -> String {
+    String::from("end-notification-group")
+}
+
+fn default_eww_overflow_widget() -> String {
+    String::from("end-notification-overflow")
+}
+
+fn default_image_cache_size() -> u32 {
+    64
+}
+
+fn default_dedup_history_window() -> u32 {
+    60
+}
+
+fn default_sound_theme() -> String {
+    String::from("freedesktop")
 }
 
 impl Default for Config {
@@ -70,6 +221,38 @@ impl Default for Config {
                 critical: 0,
             },
             update_history: false,
+            dnd_allow_critical: true,
+            persist_history: false,
+            history_file: String::new(),
+            group_notifications: false,
+            eww_group_widget: default_eww_group_widget(),
+            max_visible: MaxVisibleConfig::default(),
+            eww_overflow_widget: default_eww_overflow_widget(),
+            sound_player: String::new(),
+            sound_theme: default_sound_theme(),
+            sounds: SoundConfig::default(),
+            image_cache_size: default_image_cache_size(),
+            dedup_history_window: default_dedup_history_window(),
+            rate_limit: RateLimitConfig::default(),
+            eww_state_vars: StateVarsConfig::default(),
+            rules: Vec::new(),
+            apps: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Path of the file the history gets persisted to. Falls back to
+    /// `$XDG_STATE_HOME/end-rs/history.jsonl` when `history_file` is empty
+    pub fn history_path(&self) -> String {
+        if self.history_file.is_empty() {
+            let xdg_state_home = env::var("XDG_STATE_HOME")
+                .unwrap_or_else(|_| format!("{}/.local/state", env::var("HOME").unwrap()));
+            format!("{}/end-rs/history.jsonl", xdg_state_home)
+        } else if let Some(path) = self.history_file.strip_prefix('~') {
+            format!("{}{}", env::var("HOME").unwrap(), path)
+        } else {
+            self.history_file.clone()
         }
     }
 }
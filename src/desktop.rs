@@ -0,0 +1,58 @@
+use std::fs;
+
+use crate::log;
+use crate::utils::xdg_data_dirs;
+
+/// The parts of a desktop entry used to present an application
+#[derive(Clone, Debug)]
+pub struct DesktopEntry {
+    /// Desktop file ID without the `.desktop` suffix, e.g. `org.mozilla.firefox`
+    pub id: String,
+    pub name: String,
+    pub icon: String,
+}
+
+/// Reads the unlocalized Name and Icon keys of the `[Desktop Entry]` group
+fn parse_desktop_entry(id: &str, contents: &str) -> Option<DesktopEntry> {
+    let mut in_main_group = false;
+    let mut name = None;
+    let mut icon = String::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_main_group = line == "[Desktop Entry]";
+            continue;
+        }
+        if !in_main_group {
+            continue;
+        }
+        if let Some((key, value)) = line.split_once('=') {
+            match key.trim() {
+                "Name" => name = Some(value.trim().to_string()),
+                "Icon" => icon = value.trim().to_string(),
+                _ => {}
+            }
+        }
+    }
+    Some(DesktopEntry {
+        id: id.to_string(),
+        name: name?,
+        icon,
+    })
+}
+
+/// Looks up a desktop entry by its ID in the `applications` directories of the XDG data dirs
+pub fn load_desktop_entry(id: &str) -> Option<DesktopEntry> {
+    let id = id.strip_suffix(".desktop").unwrap_or(id);
+    if id.is_empty() || id.contains('/') {
+        return None;
+    }
+    for dir in xdg_data_dirs() {
+        let path = format!("{}/applications/{}.desktop", dir, id);
+        if let Ok(contents) = fs::read_to_string(&path) {
+            log!("Found desktop entry {}", path);
+            return parse_desktop_entry(id, &contents);
+        }
+    }
+    None
+}
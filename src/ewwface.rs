@@ -1,6 +1,6 @@
 use crate::config::{Config, NotificationWindow};
 use crate::log;
-use crate::notifdaemon::{HistoryNotification, Notification};
+use crate::notifdaemon::{visible_notification_ids, HistoryNotification, Notification};
 use serde_json::json;
 use std::collections::HashMap;
 
@@ -101,53 +101,173 @@ pub fn eww_update_value(cfg: &Config, var: &str, value: &str) {
     log!("{} updated", var);
 }
 
+/// Updates several variables with a single eww call. Variables with an empty name are skipped.
+pub fn eww_update_values(cfg: &Config, vars: &[(&str, String)]) {
+    let mut cmd = String::new();
+    cmd.push_str(&cfg.eww_binary_path);
+    cmd.push_str(" update");
+    let mut updated = false;
+    for (var, value) in vars.iter().filter(|(var, _)| !var.is_empty()) {
+        log!("Updating {} with {}", var, value);
+        cmd.push(' ');
+        cmd.push_str(var);
+        cmd.push('=');
+        cmd.push_str(&shlex::try_quote(value).unwrap());
+        updated = true;
+    }
+    if !updated {
+        return;
+    }
+    std::process::Command::new("sh")
+        .arg("-c")
+        .arg(&cmd)
+        .spawn()
+        .expect("Failed to execute command")
+        .wait()
+        .expect("Failed to execute command");
+}
+
 pub fn quote_hexator(s: &str) -> String {
     s.replace('"', "&#34;").replace('\'', "&#39;")
 }
 
-pub fn eww_create_notifications_value(cfg: &Config, notifs: &HashMap<u32, Notification>) -> String {
+fn eww_notification_json(id: &u32, notif: &Notification) -> serde_json::Value {
+    let actions: Vec<_> = notif
+        .actions
+        .iter()
+        .map(|(id, text)| json!({"id": quote_hexator(id), "text": quote_hexator(text)}))
+        .collect();
+
+    json!({
+        "actions": actions,
+        "application": quote_hexator(&notif.app_name),
+        "body": quote_hexator(&notif.body),
+        "body_markup": quote_hexator(&notif.body_markup),
+        "icon": quote_hexator(&notif.icon),
+        "app_icon": quote_hexator(&notif.app_icon),
+        "id": id,
+        "summary": quote_hexator(&notif.summary),
+        "urgency": quote_hexator(&notif.urgency),
+        "progress": notif.progress,
+        "count": notif.count,
+        "desktop_entry": quote_hexator(&notif.desktop_entry),
+    })
+}
+
+fn eww_create_notification_widget(cfg: &Config, id: &u32, notif: &Notification) -> String {
+    format!(
+        "(box ({} :notification '{}'))",
+        notif
+            .widget
+            .as_deref()
+            .unwrap_or(&cfg.eww_notification_widget),
+        serde_json::to_string(&eww_notification_json(id, notif)).unwrap()
+    )
+}
+
+/// Creates a group widget for notifications sent by the same application. `members` is sorted
+/// oldest first, so the last member provides the summary shown for the collapsed group.
+fn eww_create_group_widget(cfg: &Config, members: &[(&u32, &Notification)]) -> String {
+    let (_, latest) = members[members.len() - 1];
+    let notifications: Vec<_> = members
+        .iter()
+        .map(|(id, notif)| eww_notification_json(id, notif))
+        .collect();
+    let group_json = eww_val!({
+        "application": quote_hexator(&latest.app_name),
+        "app_icon": quote_hexator(&latest.app_icon),
+        "count": members.len(),
+        "expanded": members.iter().any(|(_, notif)| notif.group_expanded),
+        "summary": quote_hexator(&latest.summary),
+        "body": quote_hexator(&latest.body),
+        "body_markup": quote_hexator(&latest.body_markup),
+        "urgency": quote_hexator(&latest.urgency),
+        "notifications": notifications,
+    });
+    format!("(box ({} :group '{}'))", cfg.eww_group_widget, group_json)
+}
+
+fn eww_create_notifications_widgets<'a>(
+    cfg: &Config,
+    notifs: impl Iterator<Item = (&'a u32, &'a Notification)>,
+    queued: usize,
+) -> String {
     let mut widgets = format!(
         "(box :space-evenly false :orientation \"{}\" ",
         cfg.notification_orientation
     );
 
-    for notif in notifs {
-        let actions: Vec<_> = notif
-            .1
-            .actions
-            .iter()
-            .map(|(id, text)| json!({"id": quote_hexator(id), "text": quote_hexator(text)}))
-            .collect();
-
-        let widget_json = eww_val!({
-            "actions": actions,
-            "application": quote_hexator(&notif.1.app_name),
-            "body": quote_hexator(&notif.1.body),
-            "icon": quote_hexator(&notif.1.icon),
-            "app_icon": quote_hexator(&notif.1.app_icon),
-            "id": &notif.0,
-            "summary": quote_hexator(&notif.1.summary),
-            "urgency": quote_hexator(&notif.1.urgency),
-        });
+    if cfg.group_notifications {
+        let mut notifs: Vec<_> = notifs.collect();
+        notifs.sort_by_key(|(id, _)| **id);
+        let mut groups: Vec<Vec<(&u32, &Notification)>> = Vec::new();
+        for notif in notifs {
+            match groups
+                .iter_mut()
+                .find(|group| group[0].1.app_name == notif.1.app_name)
+            {
+                Some(group) => group.push(notif),
+                None => groups.push(vec![notif]),
+            }
+        }
+        for group in groups {
+            if group.len() == 1 {
+                widgets.push_str(&eww_create_notification_widget(cfg, group[0].0, group[0].1));
+            } else {
+                widgets.push_str(&eww_create_group_widget(cfg, &group));
+            }
+        }
+    } else {
+        for notif in notifs {
+            widgets.push_str(&eww_create_notification_widget(cfg, notif.0, notif.1));
+        }
+    }
 
-        let widget_string = format!(
-            "(box ({} :notification '{}'))",
-            cfg.eww_notification_widget, widget_json
-        );
-        widgets.push_str(&widget_string);
+    if queued > 0 {
+        widgets.push_str(&format!(
+            "(box ({} :count {}))",
+            cfg.eww_overflow_widget, queued
+        ));
     }
 
     widgets.push(')');
     widgets
 }
 
+/// Creates the literal for the notifications shown in the default notification window(s),
+/// followed by an entry counting the ones waiting in the queue
+pub fn eww_create_notifications_value(cfg: &Config, notifs: &HashMap<u32, Notification>) -> String {
+    let visible = visible_notification_ids(cfg, notifs);
+    eww_create_notifications_widgets(
+        cfg,
+        notifs
+            .iter()
+            .filter(|(id, n)| n.window.is_none() && visible.contains(id)),
+        notifs.len() - visible.len(),
+    )
+}
+
+/// The windows rules can route notifications to
+fn eww_rule_windows(cfg: &Config) -> Vec<&str> {
+    let mut windows: Vec<&str> = Vec::new();
+    for window in cfg.rules.iter().filter_map(|rule| rule.window.as_deref()) {
+        if !windows.contains(&window) {
+            windows.push(window);
+        }
+    }
+    windows
+}
+
+/// The variable holding the literal for the notifications routed to `window` by a rule
+pub fn eww_routed_notification_var(cfg: &Config, window: &str) -> String {
+    format!("{}-{}", cfg.eww_notification_var, window)
+}
+
 pub fn eww_create_reply_widget(cfg: &Config, id: u32) -> String {
     format!("(box ({} :id {}))", cfg.eww_reply_widget, id)
 }
 
-pub fn eww_update_notifications(cfg: &Config, notifs: &HashMap<u32, Notification>) {
-    let widgets = eww_create_notifications_value(cfg, notifs);
-    eww_update_value(cfg, &cfg.eww_notification_var, &widgets);
+fn eww_open_notification_windows(cfg: &Config) {
     match &cfg.eww_notification_window {
         NotificationWindow::Single(window) => {
             let _res = eww_open_window(cfg, window);
@@ -160,7 +280,7 @@ pub fn eww_update_notifications(cfg: &Config, notifs: &HashMap<u32, Notification
     }
 }
 
-pub fn eww_close_notifications(cfg: &Config) {
+fn eww_close_notification_windows(cfg: &Config) {
     match &cfg.eww_notification_window {
         NotificationWindow::Single(window) => {
             let _res = eww_close_window(cfg, window);
@@ -173,26 +293,129 @@ pub fn eww_close_notifications(cfg: &Config) {
     }
 }
 
+/// Updates the active count and per-app count variables from `eww_state_vars`
+pub fn eww_update_notification_state(cfg: &Config, notifs: &HashMap<u32, Notification>) {
+    let mut app_counts: HashMap<&str, usize> = HashMap::new();
+    for notif in notifs.values() {
+        *app_counts.entry(&notif.app_name).or_default() += 1;
+    }
+    let vars = &cfg.eww_state_vars;
+    eww_update_values(
+        cfg,
+        &[
+            (&vars.active_count, notifs.len().to_string()),
+            (&vars.app_counts, json!(app_counts).to_string()),
+        ],
+    );
+}
+
+/// Updates the history count and unread count variables from `eww_state_vars`
+pub fn eww_update_history_state(cfg: &Config, history: &[HistoryNotification]) {
+    let unread = history.iter().filter(|entry| !entry.read).count();
+    let vars = &cfg.eww_state_vars;
+    eww_update_values(
+        cfg,
+        &[
+            (&vars.history_count, history.len().to_string()),
+            (&vars.unread_count, unread.to_string()),
+        ],
+    );
+}
+
+pub fn eww_update_dnd_state(cfg: &Config, dnd: bool) {
+    eww_update_values(cfg, &[(&cfg.eww_state_vars.dnd, dnd.to_string())]);
+}
+
+pub fn eww_update_notifications(cfg: &Config, notifs: &HashMap<u32, Notification>) {
+    eww_update_notification_state(cfg, notifs);
+    let widgets = eww_create_notifications_value(cfg, notifs);
+    eww_update_value(cfg, &cfg.eww_notification_var, &widgets);
+    let visible = visible_notification_ids(cfg, notifs);
+    let queued = notifs.len() > visible.len();
+    if queued || visible.iter().any(|id| notifs[id].window.is_none()) {
+        eww_open_notification_windows(cfg);
+    } else if !notifs.is_empty() {
+        eww_close_notification_windows(cfg);
+    }
+
+    for window in eww_rule_windows(cfg) {
+        let routed = notifs
+            .iter()
+            .filter(|(id, n)| n.window.as_deref() == Some(window) && visible.contains(id));
+        let widgets = eww_create_notifications_widgets(cfg, routed.clone(), 0);
+        eww_update_value(cfg, &eww_routed_notification_var(cfg, window), &widgets);
+        if routed.count() > 0 {
+            let _res = eww_open_window(cfg, window);
+        } else {
+            let _res = eww_close_window(cfg, window);
+        }
+    }
+}
+
+pub fn eww_close_notifications(cfg: &Config) {
+    eww_close_notification_windows(cfg);
+    for window in eww_rule_windows(cfg) {
+        let _res = eww_close_window(cfg, window);
+    }
+}
+
+fn eww_format_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%H:%M")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
 pub fn eww_create_history_value(cfg: &Config, history: &[HistoryNotification]) -> String {
+    let entries: Vec<_> = history.iter().rev().enumerate().collect();
+    eww_create_history_entries_value(cfg, &entries)
+}
+
+/// Creates the history literal for a subset of the history. Every entry is paired with its
+/// index in the full history (newest first).
+pub fn eww_create_history_entries_value(
+    cfg: &Config,
+    entries: &[(usize, &HistoryNotification)],
+) -> String {
     let mut history_text = "(box :space-evenly false :orientation \"".to_string();
     history_text.push_str(&cfg.notification_orientation);
     history_text.push_str("\" ");
 
-    let history = history.iter().rev();
-
-    for hist in history {
+    for (index, hist) in entries {
         // NOTE: Keeping this as a comment for future reference in case eww_val! is not working
         // let widget_string = format!("({} :history \"{{\\\"app_name\\\":\\\"{}\\\",\\\"body\\\":\\\"{}\\\",\\\"icon\\\":\\\"{}\\\",\\\"app_icon\\\":\\\"{}\\\",\\\"summary\\\":\\\"{}\\\"}}\")", cfg.eww_history_widget, hist.app_name, hist.body, hist.icon, hist.app_icon, hist.summary);
+        let actions: Vec<_> = hist
+            .actions
+            .iter()
+            .map(|(id, text)| json!({"id": id, "text": text}))
+            .collect();
         let widget_string = format!(
             "(box ({} :history `{}`))",
             cfg.eww_history_widget,
             eww_val!({
+                "id": hist.id,
+                "index": index,
                 "app_name": hist.app_name,
                 "body": hist.body,
+                "body_markup": hist.body_markup,
                 "icon": hist.icon,
                 "app_icon": hist.app_icon,
                 "summary": hist.summary,
-                "urgency": hist.urgency
+                "urgency": hist.urgency,
+                "progress": hist.progress,
+                "time": eww_format_time(hist.timestamp),
+                "timestamp": hist.timestamp,
+                "closed_time": hist.closed_at.map(eww_format_time).unwrap_or_default(),
+                "closed_timestamp": hist.closed_at,
+                "close_reason": hist.close_reason.map(|reason| reason.name()).unwrap_or_default(),
+                "actions": actions,
+                "category": hist.category,
+                "desktop_entry": hist.desktop_entry,
+                "read": hist.read,
+                "count": hist.count
             })
         );
         history_text.push_str(&widget_string);
@@ -204,6 +427,7 @@ pub fn eww_create_history_value(cfg: &Config, history: &[HistoryNotification]) -
 pub fn eww_update_history(cfg: &Config, history: &[HistoryNotification]) {
     let widgets = eww_create_history_value(cfg, history);
     eww_update_value(cfg, &cfg.eww_history_var, &widgets);
+    eww_update_history_state(cfg, history);
 }
 
 pub fn eww_update_and_open_history(cfg: &Config, history: &[HistoryNotification]) {
@@ -211,6 +435,12 @@ pub fn eww_update_and_open_history(cfg: &Config, history: &[HistoryNotification]
     let _res = eww_open_window(cfg, &cfg.eww_history_window);
 }
 
+pub fn eww_show_history_entries(cfg: &Config, entries: &[(usize, &HistoryNotification)]) {
+    let widgets = eww_create_history_entries_value(cfg, entries);
+    eww_update_value(cfg, &cfg.eww_history_var, &widgets);
+    let _res = eww_open_window(cfg, &cfg.eww_history_window);
+}
+
 pub fn eww_close_history(cfg: &Config) {
     let _res = eww_close_window(cfg, &cfg.eww_history_window);
 }
@@ -218,5 +448,6 @@ pub fn eww_close_history(cfg: &Config) {
 pub fn eww_toggle_history(cfg: &Config, history: &[HistoryNotification]) {
     let widgets = eww_create_history_value(cfg, history);
     eww_update_value(cfg, &cfg.eww_history_var, &widgets);
+    eww_update_history_state(cfg, history);
     let _res = eww_toggle_window(cfg, &cfg.eww_history_window);
 }
@@ -0,0 +1,327 @@
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone};
+use serde::{Deserialize, Serialize};
+
+use crate::notifdaemon::HistoryNotification;
+
+/// Filter for `end-rs history search`
+#[derive(Default, Debug, Serialize, Deserialize)]
+pub struct HistoryFilter {
+    /// Case insensitive text searched for in the app name, summary and body
+    pub query: String,
+    pub app: Option<String>,
+    pub urgency: Option<String>,
+    /// Unix timestamps bounding the arrival time
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, entry: &HistoryNotification) -> bool {
+        let query = self.query.to_lowercase();
+        (query.is_empty()
+            || entry.app_name.to_lowercase().contains(&query)
+            || entry.summary.to_lowercase().contains(&query)
+            || entry.body.to_lowercase().contains(&query))
+            && self
+                .app
+                .as_ref()
+                .is_none_or(|app| entry.app_name.eq_ignore_ascii_case(app))
+            && self
+                .urgency
+                .as_ref()
+                .is_none_or(|urgency| &entry.urgency == urgency)
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+            && self.until.is_none_or(|until| entry.timestamp <= until)
+    }
+
+    /// Builds a filter from the arguments following `history search`
+    pub fn from_args(args: &[String]) -> Result<HistoryFilter, String> {
+        let mut filter = HistoryFilter::default();
+        let mut query = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("Missing value for {}", flag))
+            };
+            match arg.as_str() {
+                "--app" => filter.app = Some(value(arg)?),
+                "--urgency" => filter.urgency = Some(value(arg)?),
+                "--since" => filter.since = Some(parse_time(&value(arg)?)?),
+                "--until" => filter.until = Some(parse_time(&value(arg)?)?),
+                flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+                word => query.push(word),
+            }
+        }
+        filter.query = query.join(" ");
+        Ok(filter)
+    }
+}
+
+/// Which history entries `end-rs history clear` and `end-rs history remove` delete
+#[derive(Debug, Serialize, Deserialize)]
+pub enum HistorySelection {
+    All,
+    App(String),
+    Id(u32),
+    /// Position in the history, 0 being the newest entry
+    Index(usize),
+}
+
+impl HistorySelection {
+    pub fn matches(&self, index: usize, entry: &HistoryNotification) -> bool {
+        match self {
+            HistorySelection::All => true,
+            HistorySelection::App(app) => entry.app_name.eq_ignore_ascii_case(app),
+            HistorySelection::Id(id) => entry.id == *id,
+            HistorySelection::Index(i) => index == *i,
+        }
+    }
+}
+
+/// Parses a point in time into a unix timestamp. Accepts durations into the past (`90s`, `30m`,
+/// `2h`, `1d`), `HH:MM` for today, `YYYY-MM-DD`, `YYYY-MM-DD HH:MM` and unix timestamps prefixed
+/// with `@` like in `date`, so that a bare year isn't mistaken for one.
+pub fn parse_time(time: &str) -> Result<i64, String> {
+    let time = time.trim();
+    let now = Local::now();
+    let digits = |text: &str| !text.is_empty() && text.chars().all(|c| c.is_ascii_digit());
+    if let Some(unit) = time.chars().last().filter(|unit| "smhd".contains(*unit)) {
+        let amount = &time[..time.len() - 1];
+        if digits(amount) {
+            let unit_seconds = match unit {
+                's' => 1,
+                'm' => 60,
+                'h' => 60 * 60,
+                _ => 60 * 60 * 24,
+            };
+            return amount
+                .parse::<i64>()
+                .ok()
+                .and_then(|amount| amount.checked_mul(unit_seconds))
+                .and_then(|seconds| now.timestamp().checked_sub(seconds))
+                .ok_or_else(|| format!("Duration {} is too long", time));
+        }
+    }
+    if let Some(timestamp) = time.strip_prefix('@').filter(|timestamp| digits(timestamp)) {
+        return timestamp
+            .parse::<i64>()
+            .map_err(|_| format!("Invalid timestamp {}", time));
+    }
+    let local = |datetime: NaiveDateTime| {
+        Local
+            .from_local_datetime(&datetime)
+            .earliest()
+            .map(|datetime| datetime.timestamp())
+            .ok_or_else(|| format!("Invalid local time {}", time))
+    };
+    if let Ok(clock) = NaiveTime::parse_from_str(time, "%H:%M") {
+        return local(now.date_naive().and_time(clock));
+    }
+    if let Ok(datetime) = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M") {
+        return local(datetime);
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return local(date.and_time(NaiveTime::MIN));
+    }
+    Err(format!("Invalid time {}", time))
+}
+
+fn format_timestamp(timestamp: i64) -> String {
+    Local
+        .timestamp_opt(timestamp, 0)
+        .single()
+        .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_default()
+}
+
+/// Formats history entries, paired with their index (newest first), as a table
+pub fn format_table(entries: &[(usize, &HistoryNotification)]) -> String {
+    let mut rows = vec![[
+        "INDEX".to_string(),
+        "ID".to_string(),
+        "TIME".to_string(),
+        "APP".to_string(),
+        "URGENCY".to_string(),
+        "SUMMARY".to_string(),
+        "BODY".to_string(),
+    ]];
+    for (index, entry) in entries {
+        rows.push([
+            index.to_string(),
+            entry.id.to_string(),
+            format_timestamp(entry.timestamp),
+            entry.app_name.clone(),
+            entry.urgency.clone(),
+            entry.summary.replace('\n', " "),
+            entry.body.replace('\n', " "),
+        ]);
+    }
+
+    let mut widths = [0; 7];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Formats history entries, paired with their index (newest first), as a JSON array
+pub fn format_json(entries: &[(usize, &HistoryNotification)]) -> String {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|(index, entry)| {
+            let mut value = serde_json::to_value(entry).unwrap();
+            value["index"] = serde_json::json!(index);
+            value
+        })
+        .collect();
+    serde_json::to_string_pretty(&entries).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn entry(app_name: &str, summary: &str, urgency: &str, timestamp: i64) -> HistoryNotification {
+        serde_json::from_value(serde_json::json!({
+            "app_name": app_name,
+            "icon": "",
+            "app_icon": "",
+            "summary": summary,
+            "body": "Body",
+            "urgency": urgency,
+            "timestamp": timestamp,
+        }))
+        .unwrap()
+    }
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> i64 {
+        Local
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .earliest()
+            .unwrap()
+            .timestamp()
+    }
+
+    #[test]
+    fn parses_durations() {
+        let now = Local::now().timestamp();
+        for (time, seconds) in [("90s", 90), ("30m", 1800), ("2h", 7200), ("1d", 86400)] {
+            let parsed = parse_time(time).unwrap();
+            assert!(
+                (now - seconds..=now - seconds + 1).contains(&parsed),
+                "{}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_durations() {
+        assert!(parse_time("-5m").is_err());
+        assert!(parse_time("+5m").is_err());
+        assert!(parse_time("m").is_err());
+        assert!(parse_time(&format!("{}d", i64::MAX / 2)).is_err());
+    }
+
+    #[test]
+    fn parses_clock_time_as_today() {
+        let today = Local::now().date_naive();
+        let expected = Local
+            .from_local_datetime(&today.and_hms_opt(14, 30, 0).unwrap())
+            .earliest()
+            .unwrap()
+            .timestamp();
+        assert_eq!(parse_time("14:30"), Ok(expected));
+        assert!(parse_time("25:00").is_err());
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_time("2024-06-01"), Ok(local(2024, 6, 1, 0, 0)));
+        assert_eq!(
+            parse_time("2024-06-01 14:30"),
+            Ok(local(2024, 6, 1, 14, 30))
+        );
+        assert_eq!(parse_time(" 2024-06-01 "), Ok(local(2024, 6, 1, 0, 0)));
+        assert!(parse_time("2024-13-01").is_err());
+    }
+
+    #[test]
+    fn parses_timestamps_only_with_prefix() {
+        assert_eq!(parse_time("@1717245000"), Ok(1717245000));
+        assert!(parse_time("2024").is_err());
+        assert!(parse_time("@").is_err());
+        assert!(parse_time("@-5").is_err());
+        assert!(parse_time("@99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn builds_filter_from_args() {
+        let filter = HistoryFilter::from_args(&args(&[
+            "build",
+            "--app",
+            "CI",
+            "failed",
+            "--urgency",
+            "critical",
+            "--since",
+            "@100",
+            "--until",
+            "@200",
+        ]))
+        .unwrap();
+        assert_eq!(filter.query, "build failed");
+        assert_eq!(filter.app.as_deref(), Some("CI"));
+        assert_eq!(filter.urgency.as_deref(), Some("critical"));
+        assert_eq!(filter.since, Some(100));
+        assert_eq!(filter.until, Some(200));
+    }
+
+    #[test]
+    fn rejects_bad_args() {
+        assert_eq!(
+            HistoryFilter::from_args(&args(&["--verbose"])).unwrap_err(),
+            "Unknown option --verbose"
+        );
+        assert_eq!(
+            HistoryFilter::from_args(&args(&["query", "--app"])).unwrap_err(),
+            "Missing value for --app"
+        );
+        assert!(HistoryFilter::from_args(&args(&["--since", "soon"])).is_err());
+    }
+
+    #[test]
+    fn matches_entries() {
+        let entry = entry("Signal", "New message", "normal", 150);
+        let filter = |args_: &[&str]| HistoryFilter::from_args(&args(args_)).unwrap();
+        assert!(filter(&[]).matches(&entry));
+        assert!(filter(&["MESSAGE"]).matches(&entry));
+        assert!(filter(&["body"]).matches(&entry));
+        assert!(!filter(&["missing"]).matches(&entry));
+        assert!(filter(&["--app", "signal"]).matches(&entry));
+        assert!(!filter(&["--app", "sig"]).matches(&entry));
+        assert!(!filter(&["--urgency", "critical"]).matches(&entry));
+        assert!(filter(&["--since", "@150", "--until", "@150"]).matches(&entry));
+        assert!(!filter(&["--since", "@151"]).matches(&entry));
+        assert!(!filter(&["--until", "@149"]).matches(&entry));
+    }
+}
@@ -2,9 +2,13 @@ use std::env;
 use zbus::fdo::Result;
 
 pub mod config;
+pub mod desktop;
 pub mod ewwface;
 pub mod generator;
+pub mod history;
+pub mod markup;
 pub mod notifdaemon;
+pub mod rules;
 pub mod socktools;
 pub mod utils;
 
@@ -19,7 +23,17 @@ fn print_help() {
     println!("  daemon - Start the notification daemon");
     println!("  close <id> - Close a notification with the given ID");
     println!("  history <open|close|toggle> - Open, close or toggle the notification history");
+    println!("  history action <index|--id <id>> <action> - Perform an action on a history entry");
+    println!("  history clear [--app <app>] - Clear the history, or only the entries of an app");
+    println!("  history remove <index|--id <id>> - Remove a single entry from the history");
+    println!("  history mark-read [id] - Mark the history, or a single notification, as read");
+    println!("  history search <query> [--app <app>] [--urgency <urgency>] [--since <time>]");
+    println!("      [--until <time>] [--json] [--show] - Search the notification history");
     println!("  action <id> <action> - Perform an action on a notification with the given ID");
+    println!("  status - Print the number of active, history and unread notifications");
+    println!("  dnd <on|off|toggle|status> - Control or query Do Not Disturb mode");
+    println!("  group <expand|collapse> <app> - Expand or collapse the notifications of an app");
+    println!("  hover <id> <enter|leave> - Pause or resume the timeout of a notification");
     println!();
     println!("  generate [css|yuck|all] - Generate the eww config files");
 }
@@ -66,8 +80,10 @@ async fn main() -> Result<()> {
         println!("Notification Daemon running...");
         socktools::run_daemon(cfg).await?;
     } else {
-        socktools::send_message(args[1..].to_vec()).await?;
-        println!("Message sent");
+        match socktools::send_message(args[1..].to_vec()).await? {
+            Some(reply) => println!("{}", reply),
+            None => println!("Message sent"),
+        }
     }
 
     Ok(())
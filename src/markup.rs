@@ -0,0 +1,229 @@
+/// A notification body with the markup allowed by the `body-markup` capability
+pub struct Body {
+    /// Pango markup restricted to `<b>`, `<i>` and `<u>`, safe to use with eww's `:markup`
+    pub markup: String,
+    /// The text of the body without any markup
+    pub plain: String,
+}
+
+const ALLOWED_TAGS: [&str; 3] = ["b", "i", "u"];
+
+/// Decodes the named entities allowed in the spec and numeric character references. Unknown
+/// entities are kept as they are.
+pub fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some('\u{a0}'),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32)
+                // NUL would cut the string short in eww
+                .filter(|character| *character != '\0'),
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&#34;")
+        .replace('\'', "&#39;")
+}
+
+/// Returns the value of `name` in the attributes of a tag, e.g. `alt` in `img src="a" alt="b"`
+fn tag_attribute(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    while let Some(start) = rest.find(name) {
+        let after = rest[start + name.len()..].trim_start();
+        let boundary = start == 0 || rest[..start].ends_with(char::is_whitespace);
+        if boundary {
+            if let Some(value) = after.strip_prefix('=') {
+                let value = value.trim_start();
+                let quote = value.chars().next()?;
+                if quote == '"' || quote == '\'' {
+                    let end = value[1..].find(quote)?;
+                    return Some(decode_entities(&value[1..end + 1]));
+                }
+                let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                return Some(decode_entities(&value[..end]));
+            }
+        }
+        rest = &rest[start + name.len()..];
+    }
+    None
+}
+
+fn push_text(body: &mut Body, text: &str) {
+    let text = decode_entities(text);
+    body.markup.push_str(&escape_markup(&text));
+    body.plain.push_str(&text);
+}
+
+/// Parses a notification body into a safe subset of Pango markup and plain text. `<b>`, `<i>`
+/// and `<u>` are kept, links are underlined, images are replaced with their alt text and every
+/// other tag is dropped while keeping its content.
+pub fn parse_body(body: &str) -> Body {
+    let mut parsed = Body {
+        markup: String::with_capacity(body.len()),
+        plain: String::with_capacity(body.len()),
+    };
+    let mut open_tags: Vec<&'static str> = Vec::new();
+    let mut rest = body;
+
+    while let Some(start) = rest.find('<') {
+        push_text(&mut parsed, &rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = rest[1..end].trim();
+        rest = &rest[end + 1..];
+
+        let closing = tag.starts_with('/');
+        let tag = tag.trim_start_matches('/').trim_end_matches('/').trim();
+        let name = tag
+            .split(|c: char| c.is_whitespace())
+            .next()
+            .unwrap_or("")
+            .to_lowercase();
+        // Links are shown underlined as the target can't be passed through eww safely
+        let name = if name == "a" { "u".to_string() } else { name };
+
+        if name == "img" {
+            if let Some(alt) = tag_attribute(tag, "alt") {
+                parsed.markup.push_str(&escape_markup(&alt));
+                parsed.plain.push_str(&alt);
+            }
+        } else if let Some(allowed) = ALLOWED_TAGS.iter().find(|allowed| **allowed == name) {
+            if !closing {
+                open_tags.push(allowed);
+                parsed.markup.push_str(&format!("<{}>", allowed));
+            } else if open_tags.contains(allowed) {
+                // Close the tags opened inside this one to keep the markup well formed
+                while let Some(open) = open_tags.pop() {
+                    parsed.markup.push_str(&format!("</{}>", open));
+                    if open == *allowed {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    push_text(&mut parsed, rest);
+
+    while let Some(open) = open_tags.pop() {
+        parsed.markup.push_str(&format!("</{}>", open));
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &str) -> (String, String) {
+        let body = parse_body(body);
+        (body.markup, body.plain)
+    }
+
+    #[test]
+    fn keeps_allowed_tags() {
+        assert_eq!(
+            parse("<b>bold</b> <I>italic</I> <u>under</u>"),
+            (
+                "<b>bold</b> <i>italic</i> <u>under</u>".to_string(),
+                "bold italic under".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn drops_other_tags_but_keeps_their_content() {
+        assert_eq!(
+            parse("<span color=\"red\">red</span><br/>line"),
+            ("redline".to_string(), "redline".to_string())
+        );
+    }
+
+    #[test]
+    fn closes_unbalanced_tags() {
+        assert_eq!(parse("<b>open").0, "<b>open</b>");
+        assert_eq!(parse("stray</b>").0, "stray");
+        assert_eq!(
+            parse("<b><i>nested</b>after</i>").0,
+            "<b><i>nested</i></b>after"
+        );
+    }
+
+    #[test]
+    fn underlines_links() {
+        assert_eq!(
+            parse("<a href=\"https://example.com\">link</a>"),
+            ("<u>link</u>".to_string(), "link".to_string())
+        );
+    }
+
+    #[test]
+    fn replaces_images_with_alt_text() {
+        assert_eq!(
+            parse("<img src=\"a.png\" alt=\"a &amp; b\"/> <img src='b.png'>"),
+            ("a &amp; b ".to_string(), "a & b ".to_string())
+        );
+        assert_eq!(parse("<img alt=smile>").1, "smile");
+    }
+
+    #[test]
+    fn decodes_entities() {
+        assert_eq!(
+            decode_entities("&lt;&gt;&amp;&quot;&apos;&#65;&#x42;&#X43;"),
+            "<>&\"'ABC"
+        );
+        assert_eq!(decode_entities("&unknown; & &#xzz;"), "&unknown; & &#xzz;");
+        assert_eq!(decode_entities("&#0;"), "&#0;");
+    }
+
+    #[test]
+    fn escapes_text() {
+        assert_eq!(
+            parse("1 &lt; 2 & \"3\""),
+            (
+                "1 &lt; 2 &amp; &#34;3&#34;".to_string(),
+                "1 < 2 & \"3\"".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn keeps_unterminated_tag_as_text() {
+        assert_eq!(
+            parse("<b>a < b"),
+            ("<b>a &lt; b</b>".to_string(), "a < b".to_string())
+        );
+    }
+}
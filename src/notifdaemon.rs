@@ -1,22 +1,33 @@
 #![allow(clippy::too_many_arguments)]
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::{Mutex, RwLock};
 use tokio::task::JoinHandle;
-use tokio::time::sleep;
+use tokio::time::{sleep, Instant};
 use zbus::fdo::Result;
 use zbus::interface;
+use zbus::message::Header;
+use zbus::names::BusName;
 use zbus::object_server::SignalEmitter;
 use zvariant::Value;
 
 use crate::config::Config;
+use crate::desktop::{load_desktop_entry, DesktopEntry};
 use crate::ewwface::{
-    eww_close_history, eww_close_notifications, eww_close_window, eww_toggle_history,
-    eww_update_and_open_history, eww_update_history, eww_update_notifications,
+    eww_close_history, eww_close_notifications, eww_close_window, eww_is_window_open,
+    eww_show_history_entries, eww_toggle_history, eww_update_and_open_history,
+    eww_update_dnd_state, eww_update_history, eww_update_history_state, eww_update_notifications,
 };
+use crate::history::{format_json, format_table, HistoryFilter, HistorySelection};
 use crate::log;
-use crate::utils::{find_icon, save_icon};
+use crate::markup::parse_body;
+use crate::rules::{evaluate_rules, urgency_from_str, CompiledRule, RuleSubject};
+use crate::utils::{
+    append_history, find_sound, play_sound, prune_image_cache, save_history, save_icon, IconCache,
+    ICON_LOOKUP_TIMEOUT, ICON_SIZE,
+};
 
 pub struct Notification {
     pub app_name: String,
@@ -24,19 +35,84 @@ pub struct Notification {
     pub app_icon: String,
     pub summary: String,
     pub body: String,
+    pub body_markup: String,
     pub urgency: String,
+    pub progress: Option<u32>,
     pub actions: Vec<(String, String)>,
     pub timeout_cancelled: bool,
     pub timeout_future: Option<JoinHandle<()>>,
+    /// Timeout in milliseconds, started once the notification leaves the queue. While the timeout
+    /// is paused this holds the time that was left.
+    pub expire_timeout: i32,
+    pub timeout_deadline: Option<Instant>,
+    pub hovered: bool,
+    /// Resumes the timeout if no hover leave event arrives, see `HOVER_PAUSE_LIMIT`
+    pub hover_guard: Option<JoinHandle<()>>,
+    /// Resident notifications stay open after an action is invoked
+    pub resident: bool,
+    pub window: Option<String>,
+    pub widget: Option<String>,
+    pub group_expanded: bool,
+    /// How many identical notifications were merged into this one
+    pub count: u32,
+    pub desktop_entry: String,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct HistoryNotification {
+    #[serde(default)]
+    pub id: u32,
     pub app_name: String,
     pub icon: String,
     pub app_icon: String,
     pub summary: String,
     pub body: String,
+    #[serde(default)]
+    pub body_markup: String,
     pub urgency: String,
+    #[serde(default)]
+    pub progress: Option<u32>,
+    /// Arrival time as a unix timestamp
+    #[serde(default)]
+    pub timestamp: i64,
+    #[serde(default)]
+    pub closed_at: Option<i64>,
+    #[serde(default)]
+    pub close_reason: Option<CloseReason>,
+    #[serde(default)]
+    pub actions: Vec<(String, String)>,
+    #[serde(default)]
+    pub category: String,
+    #[serde(default)]
+    pub desktop_entry: String,
+    /// Set once the entry was seen in the history window
+    #[serde(default)]
+    pub read: bool,
+    /// How many identical notifications were merged into this entry
+    #[serde(default = "default_count")]
+    pub count: u32,
+    /// Unique bus name of the client, only valid while the daemon is running
+    #[serde(skip)]
+    pub sender: String,
+}
+
+fn default_count() -> u32 {
+    1
+}
+
+impl HistoryNotification {
+    /// Whether a notification with this content is a repeat of this entry
+    fn same_content(&self, app_name: &str, summary: &str, body_markup: &str) -> bool {
+        self.app_name == app_name && self.summary == summary && self.body_markup == body_markup
+    }
+}
+
+/// Popups an app showed since `started`, see `rate_limit` in the config
+pub struct RateWindow {
+    pub started: Instant,
+    pub count: u32,
+    /// The popup summarizing the notifications over the limit
+    pub summary_id: Option<u32>,
 }
 
 pub struct NotificationDaemon {
@@ -45,6 +121,217 @@ pub struct NotificationDaemon {
     pub notifications_history: Arc<RwLock<Vec<HistoryNotification>>>,
     pub connection: zbus::Connection,
     pub next_id: u32,
+    pub dnd: bool,
+    pub rate_windows: HashMap<String, RateWindow>,
+    pub icon_cache: IconCache,
+    pub rules: Vec<CompiledRule>,
+    /// Desktop entries by the ID they were looked up with, misses included
+    pub desktop_entries: HashMap<String, Option<DesktopEntry>>,
+}
+
+/// Ids of the notifications that fit within `max_visible`, the rest wait in the queue. Critical
+/// notifications are shown first, then the oldest ones.
+pub fn visible_notification_ids(cfg: &Config, notifs: &HashMap<u32, Notification>) -> Vec<u32> {
+    let limits = &cfg.max_visible;
+    let mut ids: Vec<_> = notifs.keys().copied().collect();
+    ids.sort_by_key(|id| (notifs[id].urgency != "critical", *id));
+
+    let mut visible = Vec::new();
+    let mut per_urgency: HashMap<&str, u32> = HashMap::new();
+    for id in ids {
+        let urgency = notifs[&id].urgency.as_str();
+        let limit = match urgency {
+            "low" => limits.low,
+            "critical" => limits.critical,
+            _ => limits.normal,
+        };
+        let count = per_urgency.entry(urgency).or_default();
+        if (limits.total != 0 && visible.len() as u32 >= limits.total)
+            || (limit != 0 && *count >= limit)
+        {
+            continue;
+        }
+        *count += 1;
+        visible.push(id);
+    }
+    visible
+}
+
+/// Reasons for closing a notification as defined in the spec
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CloseReason {
+    Expired = 1,
+    Dismissed = 2,
+    Closed = 3,
+    Undefined = 4,
+}
+
+impl CloseReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CloseReason::Expired => "expired",
+            CloseReason::Dismissed => "dismissed",
+            CloseReason::Closed => "closed",
+            CloseReason::Undefined => "undefined",
+        }
+    }
+}
+
+/// The parts of the daemon needed outside of a D-Bus call, e.g. by the timeout tasks
+#[derive(Clone)]
+pub struct SharedState {
+    pub notifications: Arc<Mutex<HashMap<u32, Notification>>>,
+    pub notifications_history: Arc<RwLock<Vec<HistoryNotification>>>,
+    pub config: Arc<Config>,
+    pub connection: zbus::Connection,
+}
+
+impl SharedState {
+    /// Records how a notification was closed in its history entry and emits NotificationClosed
+    pub async fn notification_closed(&self, id: u32, reason: CloseReason) {
+        log!("Notification {} closed with reason {:?}", id, reason);
+        let mut history = self.notifications_history.write().await;
+        if let Some(entry) = history.iter_mut().rev().find(|entry| entry.id == id) {
+            if entry.close_reason.is_none() {
+                entry.closed_at = Some(chrono::Local::now().timestamp());
+                entry.close_reason = Some(reason);
+                if self.config.persist_history {
+                    save_history(&self.config, &history);
+                }
+            }
+        }
+        drop(history);
+
+        let dest: Option<&str> = None;
+        if let Err(e) = self
+            .connection
+            .emit_signal(
+                dest,
+                "/org/freedesktop/Notifications",
+                "org.freedesktop.Notifications",
+                "NotificationClosed",
+                &(id, reason as u32),
+            )
+            .await
+        {
+            eprintln!("Failed to emit NotificationClosed: {}", e);
+        }
+        self.prune_image_cache().await;
+    }
+
+    /// Deletes the cached images that neither a popup nor a history entry uses anymore. The cache
+    /// directory is scanned on a blocking thread after the locks are released.
+    pub async fn prune_image_cache(&self) {
+        let notifications = self.notifications.lock().await;
+        let history = self.notifications_history.read().await;
+        let referenced: HashSet<String> = notifications
+            .values()
+            .map(|notification| notification.icon.clone())
+            .chain(history.iter().map(|entry| entry.icon.clone()))
+            .collect();
+        drop(history);
+        drop(notifications);
+        let max_size = self.config.image_cache_size as u64 * 1024 * 1024;
+        tokio::task::spawn_blocking(move || prune_image_cache(&referenced, max_size));
+    }
+}
+
+/// Longest a hover pauses the timeout. Enter and leave events come from separate eww commands and
+/// may arrive out of order, a lost leave event would otherwise keep the popup forever.
+const HOVER_PAUSE_LIMIT: Duration = Duration::from_secs(30);
+
+/// Unpauses the timeout of a hovered notification once `HOVER_PAUSE_LIMIT` passes
+fn spawn_hover_guard(state: SharedState, id: u32) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        sleep(HOVER_PAUSE_LIMIT).await;
+        let mut notifications = state.notifications.lock().await;
+        if let Some(notification) = notifications.get_mut(&id) {
+            log!("Hover of {} timed out", id);
+            notification.hovered = false;
+            notification.hover_guard = None;
+            start_visible_timeouts(&state, &mut notifications);
+        }
+    })
+}
+
+fn spawn_timeout(state: SharedState, id: u32, expire_timeout: i32) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        sleep(Duration::from_millis(expire_timeout as u64)).await;
+        // Wait for the lock, hover events keep it busy and skipping would keep the popup forever
+        let mut notifications = state.notifications.lock().await;
+        if let Some(notif) = notifications.remove(&id) {
+            if !notif.timeout_cancelled {
+                start_visible_timeouts(&state, &mut notifications);
+                eww_update_notifications(&state.config, &notifications);
+                if notifications.is_empty() {
+                    eww_close_notifications(&state.config);
+                }
+            }
+            drop(notifications);
+            state.notification_closed(id, CloseReason::Expired).await;
+        }
+    })
+}
+
+/// Closes a notification that never got a popup once its timeout passes, or as soon as possible
+/// if it would never expire, so that clients waiting for NotificationClosed don't hang
+fn close_undisplayed(state: SharedState, id: u32, expire_timeout: i32) {
+    tokio::spawn(async move {
+        let reason = if expire_timeout > 0 {
+            CloseReason::Expired
+        } else {
+            CloseReason::Undefined
+        };
+        sleep(Duration::from_millis(expire_timeout.max(0) as u64)).await;
+        // A replacement may have been shown as a popup in the meantime
+        if state.notifications.lock().await.contains_key(&id) {
+            return;
+        }
+        state.notification_closed(id, reason).await;
+    });
+}
+
+/// Starts the timeout of every visible notification that doesn't have one running yet, which
+/// are new notifications and the ones that just left the queue
+fn start_visible_timeouts(state: &SharedState, notifications: &mut HashMap<u32, Notification>) {
+    for id in visible_notification_ids(&state.config, notifications) {
+        let notification = notifications.get_mut(&id).unwrap();
+        if notification.timeout_future.is_none()
+            && notification.expire_timeout != 0
+            && !notification.hovered
+        {
+            notification.timeout_deadline =
+                Some(Instant::now() + Duration::from_millis(notification.expire_timeout as u64));
+            notification.timeout_future = Some(spawn_timeout(
+                state.clone(),
+                id,
+                notification.expire_timeout,
+            ));
+        }
+    }
+}
+
+/// Reads a boolean hint. Some clients send booleans as integers, so those are accepted too.
+fn hint_bool(hints: &HashMap<&str, Value<'_>>, name: &str) -> bool {
+    hints
+        .get(name)
+        .and_then(|value| match value {
+            Value::Bool(value) => Some(*value),
+            Value::U8(value) => Some(*value != 0),
+            Value::I32(value) => Some(*value != 0),
+            Value::U32(value) => Some(*value != 0),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+fn urgency_name(urgency: Option<u8>) -> &'static str {
+    match urgency {
+        Some(0) => "low",
+        Some(1) => "normal",
+        Some(2) => "critical",
+        _ => "normal",
+    }
 }
 
 #[interface(name = "org.freedesktop.Notifications")]
@@ -59,63 +346,185 @@ impl NotificationDaemon {
         actions: Vec<&str>,
         hints: HashMap<&str, zvariant::Value<'_>>,
         expire_timeout: i32,
+        #[zbus(header)] header: Header<'_>,
     ) -> Result<u32> {
         log!("Notifying {} - {}", app_name, body);
-        let id = if replaces_id != 0 {
+        let body = parse_body(body);
+        let desktop_entry = hints
+            .get("desktop-entry")
+            .and_then(|value| match value {
+                Value::Str(desktop_entry) => Some(desktop_entry.to_string()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let desktop = self.find_desktop_entry(&desktop_entry, app_name).await;
+        log!("Desktop entry: {:?}", desktop);
+        let desktop_entry = desktop
+            .as_ref()
+            .map(|desktop| desktop.id.clone())
+            .unwrap_or(desktop_entry);
+        let app_config = self
+            .config
+            .apps
+            .get(app_name)
+            .or_else(|| self.config.apps.get(&desktop_entry))
+            .cloned()
+            .unwrap_or_default();
+        log!("App config: {:?}", app_config);
+        // Without an Icon key the app icon is still guessed from the name the app sent
+        let app_icon_name = app_config
+            .icon
+            .clone()
+            .or_else(|| desktop.as_ref().map(|desktop| desktop.icon.clone()))
+            .filter(|icon| !icon.is_empty())
+            .unwrap_or_else(|| app_name.to_string());
+        // Rules written for the name the app sends keep working once a desktop entry renames it
+        let sent_app_name = app_name;
+        // Show the proper name, e.g. "Firefox" instead of "org.mozilla.firefox"
+        let app_name = app_config
+            .name
+            .clone()
+            .or_else(|| desktop.map(|desktop| desktop.name))
+            .unwrap_or_else(|| app_name.to_string());
+        let app_name = app_name.as_str();
+        // A repeat of an active notification bumps its count instead of adding another popup
+        let duplicate_of = if replaces_id == 0 {
+            self.notifications
+                .lock()
+                .await
+                .iter()
+                .find(|(_, n)| {
+                    n.app_name == app_name && n.summary == summary && n.body_markup == body.markup
+                })
+                .map(|(id, _)| *id)
+        } else {
+            None
+        };
+        // Only reuse the ID of a notification we know about, unknown IDs get a fresh one
+        let replaces_known = replaces_id != 0
+            && (self.notifications.lock().await.contains_key(&replaces_id)
+                || self
+                    .notifications_history
+                    .read()
+                    .await
+                    .iter()
+                    .any(|entry| entry.id == replaces_id));
+        let id = if let Some(duplicate_id) = duplicate_of {
+            duplicate_id
+        } else if replaces_known {
             replaces_id
         } else {
             self.next_id += 1;
             self.next_id
         };
         log!("ID: {}", id);
-        let icon = hints
-            .get("image_data")
-            .and_then(|value| match value {
-                Value::Structure(icon_data) => save_icon(icon_data, id),
+        let icon_deadline = Instant::now() + ICON_LOOKUP_TIMEOUT;
+        // Images in the priority order of the spec, with the deprecated hints after their
+        // replacements
+        let image_data = |name: &str| {
+            hints.get(name).and_then(|value| match value {
+                Value::Structure(icon_data) => save_icon(icon_data),
                 _ => None,
             })
-            .or_else(|| {
-                hints.get("image-data").and_then(|value| match value {
-                    Value::Structure(icon_data) => save_icon(icon_data, id),
-                    _ => None,
-                })
-            })
-            .or_else(|| {
-                if !app_name.is_empty() {
-                    find_icon(app_icon, &self.config).or_else(|| Some(app_icon.to_string()))
-                } else {
-                    None
-                }
+        };
+        let image_path = |name: &str| {
+            hints.get(name).and_then(|value| match value {
+                Value::Str(image_path) => Some(image_path.to_string()),
+                _ => None,
             })
-            .unwrap_or_else(|| app_icon.to_string());
+        };
+        let mut icon = image_data("image-data").or_else(|| image_data("image_data"));
+        for image_path in [image_path("image-path"), image_path("image_path")] {
+            if let (None, Some(image_path)) = (&icon, image_path) {
+                icon = self
+                    .icon_cache
+                    .find_icon(&image_path, ICON_SIZE, icon_deadline)
+                    .await;
+            }
+        }
+        // The configured app icon wins over the one the app sent, like for app_icon below
+        let fallback_icon = app_config
+            .icon
+            .as_deref()
+            .filter(|icon| !icon.is_empty())
+            .unwrap_or(app_icon);
+        let icon = match icon.or_else(|| image_data("icon_data")) {
+            Some(icon) => icon,
+            None if !app_name.is_empty() => self
+                .icon_cache
+                .find_icon(fallback_icon, ICON_SIZE, icon_deadline)
+                .await
+                .unwrap_or_else(|| fallback_icon.to_string()),
+            None => fallback_icon.to_string(),
+        };
         log!("Icon: {}", icon);
 
-        let app_icon = find_icon(app_name, &self.config).unwrap_or("".into());
+        let app_icon = self
+            .icon_cache
+            .find_icon(&app_icon_name, ICON_SIZE, icon_deadline)
+            .await
+            .unwrap_or_default();
 
         log!("AppIcon: {}", app_icon);
-        let urgency = hints.get("urgency").and_then(|value| match value {
-            Value::U8(urgency) => Some(*urgency),
-            _ => None,
-        });
+        let mut urgency = hints
+            .get("urgency")
+            .and_then(|value| match value {
+                Value::U8(urgency) => Some(*urgency),
+                _ => None,
+            })
+            .or_else(|| {
+                let default_urgency = app_config.urgency.as_deref()?;
+                let urgency = urgency_from_str(default_urgency);
+                if urgency.is_none() {
+                    log!("Invalid urgency in app config: {}", default_urgency);
+                }
+                urgency
+            });
+        let category = hints
+            .get("category")
+            .and_then(|value| match value {
+                Value::Str(category) => Some(category.to_string()),
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        let rule_outcome = evaluate_rules(
+            &self.rules,
+            &RuleSubject {
+                app_name,
+                sent_app_name,
+                summary,
+                body: &body.plain,
+                urgency: urgency_name(urgency),
+                category: &category,
+                desktop_entry: &desktop_entry,
+            },
+        );
+        log!("Rule outcome: {:?}", rule_outcome);
+        if rule_outcome.urgency.is_some() {
+            urgency = rule_outcome.urgency;
+        }
 
         let mut expire_timeout = expire_timeout;
-        if expire_timeout < 0 {
-            match urgency {
-                Some(0) => expire_timeout = self.config.timeout.low as i32 * 1000,
-                Some(1) => expire_timeout = self.config.timeout.normal as i32 * 1000,
-                Some(2) => expire_timeout = self.config.timeout.critical as i32 * 1000,
-                _ => expire_timeout = self.config.timeout.normal as i32 * 1000,
-            }
+        if let Some(timeout) = rule_outcome.timeout {
+            expire_timeout = timeout as i32 * 1000;
+        } else if expire_timeout < 0 {
+            let default_timeout = match urgency {
+                Some(0) => self.config.timeout.low,
+                Some(2) => self.config.timeout.critical,
+                _ => self.config.timeout.normal,
+            };
+            expire_timeout = app_config.timeout.unwrap_or(default_timeout) as i32 * 1000;
         }
 
-        let urgency_str = match urgency {
-            Some(0) => "low",
-            Some(1) => "normal",
-            Some(2) => "critical",
-            _ => "normal",
-        };
+        let urgency_str = urgency_name(urgency);
         log!("Expire timeout: {}", expire_timeout);
 
+        // In Do Not Disturb mode only critical notifications (if allowed) get a popup, the rest
+        // are only recorded in the history
+        let suppress_popup = rule_outcome.suppress_popup
+            || (self.dnd && !(urgency_str == "critical" && self.config.dnd_allow_critical));
+
         // create an actions vector of type Vec<(String, String)> where even elements are keys and
         // odd elements are values
         let actions: Vec<(String, String)> = actions
@@ -127,109 +536,238 @@ impl NotificationDaemon {
             })
             .collect();
 
-        let is_transient = hints
-            .get("transient")
-            .and_then(|value| match value {
-                Value::Bool(transient) => Some(*transient),
-                _ => None,
-            })
-            .unwrap_or(false);
+        let progress = hints.get("value").and_then(|value| match value {
+            Value::I32(value) => Some((*value).clamp(0, 100) as u32),
+            Value::U32(value) => Some((*value).min(100)),
+            Value::I64(value) => Some((*value).clamp(0, 100) as u32),
+            Value::U64(value) => Some((*value).min(100) as u32),
+            Value::U8(value) => Some((*value).min(100) as u32),
+            _ => None,
+        });
+
+        // Transient notifications never reach the history, not even when it is persisted
+        let is_transient = hint_bool(&hints, "transient") || rule_outcome.transient;
+        let is_resident = hint_bool(&hints, "resident");
 
-        if !is_transient {
+        // Whether an image of a replaced popup or history entry may have become unused
+        let mut dropped_image = false;
+        if !is_transient && !rule_outcome.skip_history {
             log!("Notification is not transient");
-            let history_notification = HistoryNotification {
+            let mut history_notification = HistoryNotification {
+                id,
                 app_name: app_name.to_string(),
                 icon: icon.clone(),
                 app_icon: app_icon.clone(),
                 summary: summary.to_string(),
-                body: body.to_string(),
+                body: body.plain.clone(),
+                body_markup: body.markup.clone(),
                 urgency: urgency_str.to_string(),
+                progress,
+                timestamp: chrono::Local::now().timestamp(),
+                closed_at: None,
+                close_reason: None,
+                actions: actions.clone(),
+                category: category.clone(),
+                desktop_entry: desktop_entry.clone(),
+                read: false,
+                count: 1,
+                sender: header
+                    .sender()
+                    .map(|sender| sender.to_string())
+                    .unwrap_or_default(),
             };
             let mut notifications_history = self.notifications_history.write().await;
-            notifications_history.push(history_notification);
+            let now = history_notification.timestamp;
+            let dedup_window = self.config.dedup_history_window as i64;
+            let replaced_entry = notifications_history
+                .iter()
+                .rposition(|entry| (replaces_known || duplicate_of.is_some()) && entry.id == id);
+            // Repeats within the window are merged into the earlier entry, which moves up
+            let repeated_entry = notifications_history.iter().rposition(|entry| {
+                dedup_window > 0
+                    && now - entry.timestamp <= dedup_window
+                    && entry.same_content(app_name, summary, &body.markup)
+            });
+            let appended = match (replaced_entry, repeated_entry) {
+                (Some(position), _) => {
+                    if duplicate_of.is_some() {
+                        history_notification.count = notifications_history[position].count + 1;
+                    }
+                    dropped_image |= notifications_history[position].icon != icon;
+                    notifications_history[position] = history_notification;
+                    false
+                }
+                (None, Some(position)) => {
+                    let repeated = notifications_history.remove(position);
+                    history_notification.count = repeated.count + 1;
+                    dropped_image |= repeated.icon != icon;
+                    notifications_history.push(history_notification);
+                    false
+                }
+                (None, None) => {
+                    notifications_history.push(history_notification);
+                    true
+                }
+            };
             log!("Updated history");
             // Release the lock before updating the notifications
             if notifications_history.len() > self.config.max_notifications as usize {
                 notifications_history.remove(0);
+                dropped_image = true;
+            }
+            if self.config.persist_history {
+                // A new entry is only appended, the entry that got pushed out stays in the file
+                // until the next rewrite and is skipped when loading
+                match notifications_history.last() {
+                    Some(entry) if appended => append_history(&self.config, entry),
+                    _ => save_history(&self.config, &notifications_history),
+                }
             }
 
             drop(notifications_history);
             if self.config.update_history {
                 self.update_history().await?;
                 log!("Updated history for update_history");
+            } else {
+                let history = self.notifications_history.read().await;
+                eww_update_history_state(&self.config, &history);
             }
             log!("Updated history");
         }
 
-        let mut join_handle = None;
-        if expire_timeout != 0 {
-            // Spawn a task to handle timeout
-            let notifications = Arc::clone(&self.notifications);
-            let config_thread = Arc::clone(&self.config);
-            join_handle = Some(tokio::spawn(async move {
-                sleep(Duration::from_millis(expire_timeout as u64)).await;
-                let notifications = notifications.try_lock();
-                if let Ok(mut notifications) = notifications {
-                    if let Some(notif) = notifications.remove(&id) {
-                        if !notif.timeout_cancelled {
-                            eww_update_notifications(&config_thread, &notifications);
-                            if notifications.is_empty() {
-                                eww_close_notifications(&config_thread);
-                            }
-                        }
+        if suppress_popup {
+            log!("Suppressed popup for {}", id);
+            close_undisplayed(self.shared_state(), id, expire_timeout);
+            if dropped_image {
+                self.shared_state().prune_image_cache().await;
+            }
+            return Ok(id);
+        }
+
+        // Replacing an active popup doesn't add a new one, so it doesn't count towards the limit
+        let replaces_popup = self.notifications.lock().await.contains_key(&id);
+        if !replaces_popup && self.rate_limited(app_name) {
+            log!("Rate limited popup for {}", id);
+            close_undisplayed(self.shared_state(), id, expire_timeout);
+            self.show_flood_summary(app_name, &app_icon).await;
+            if dropped_image {
+                self.shared_state().prune_image_cache().await;
+            }
+            return Ok(id);
+        }
+
+        if !hint_bool(&hints, "suppress-sound") {
+            let sound = hints
+                .get("sound-file")
+                .and_then(|value| match value {
+                    Value::Str(sound_file) => {
+                        let sound_file = sound_file.as_str();
+                        Some(
+                            sound_file
+                                .strip_prefix("file://")
+                                .unwrap_or(sound_file)
+                                .to_string(),
+                        )
                     }
-                }
-            }));
+                    _ => None,
+                })
+                .or_else(|| {
+                    hints.get("sound-name").and_then(|value| match value {
+                        Value::Str(sound_name) => find_sound(sound_name, &self.config),
+                        _ => None,
+                    })
+                })
+                .or_else(|| {
+                    // Updates of an active popup, e.g. progress, only play the sound they ask
+                    // for, the default one would beep on every tick
+                    if replaces_popup || duplicate_of.is_some() {
+                        return None;
+                    }
+                    let sound_name = match urgency_str {
+                        "low" => &self.config.sounds.low,
+                        "critical" => &self.config.sounds.critical,
+                        _ => &self.config.sounds.normal,
+                    };
+                    find_sound(sound_name, &self.config)
+                });
+            if let Some(sound) = sound {
+                play_sound(&sound, &self.config);
+            }
         }
 
-        let notification = Notification {
+        let mut notification = Notification {
             app_name: app_name.to_string(),
             icon: icon.clone(),
             app_icon,
             actions,
             summary: summary.to_string(),
-            body: body.to_string(),
+            body: body.plain,
+            body_markup: body.markup,
             urgency: urgency_str.to_string(),
+            progress,
             timeout_cancelled: false,
-            timeout_future: join_handle,
+            timeout_future: None,
+            expire_timeout,
+            timeout_deadline: None,
+            hovered: false,
+            hover_guard: None,
+            resident: is_resident,
+            window: rule_outcome.window,
+            widget: rule_outcome.widget,
+            group_expanded: false,
+            count: 1,
+            desktop_entry,
         };
 
-        let notifications = self.notifications.try_lock();
-        if let Ok(mut notifications) = notifications {
-            notifications.insert(id, notification);
-            eww_update_notifications(&self.config, &notifications);
+        let mut notifications = self.notifications.lock().await;
+        // Join the state of an already expanded group of the same application
+        notification.group_expanded = notifications
+            .values()
+            .any(|n| n.app_name == notification.app_name && n.group_expanded);
+        if let Some(replaced) = notifications.remove(&id) {
+            // The replacement starts with a fresh timeout
+            if let Some(timeout_future) = replaced.timeout_future {
+                timeout_future.abort();
+            }
+            notification.hovered = replaced.hovered;
+            notification.hover_guard = replaced.hover_guard;
+            if duplicate_of.is_some() {
+                notification.count = replaced.count + 1;
+            }
+            dropped_image |= replaced.icon != notification.icon;
         }
+        notifications.insert(id, notification);
+        start_visible_timeouts(&self.shared_state(), &mut notifications);
+        eww_update_notifications(&self.config, &notifications);
+        drop(notifications);
         log!("Notification with ID {} created", id);
+        // The replaced notification or a history entry that got pushed out may have been the
+        // last one using an image
+        if dropped_image {
+            self.shared_state().prune_image_cache().await;
+        }
         Ok(id)
     }
 
     pub async fn close_notification(&self, id: u32) -> Result<()> {
-        let notifications = self.notifications.try_lock();
-        if let Ok(mut notifications) = notifications {
-            if notifications.remove(&id).is_some() {
-                println!("Notification with ID {} closed", id);
-                eww_update_notifications(&self.config, &notifications);
-                if notifications.is_empty() {
-                    eww_close_notifications(&self.config);
-                }
-                let dest: Option<&str> = None;
-                self.connection
-                    .emit_signal(
-                        dest,
-                        "/org/freedesktop/Notifications",
-                        "org.freedesktop.Notifications",
-                        "NotificationClosed",
-                        &(id, 3_u32),
-                    )
-                    .await
-                    .unwrap();
-            }
-        }
-        Ok(())
+        self.close_notification_with_reason(id, CloseReason::Closed)
+            .await
     }
 
     pub fn get_capabilities(&self) -> Vec<String> {
-        vec!["body".to_string(), "actions".to_string()]
+        let mut capabilities = vec![
+            "body".to_string(),
+            "body-markup".to_string(),
+            "actions".to_string(),
+        ];
+        // Clients only skip playing sounds themselves if the server can
+        if !self.config.sound_player.is_empty() {
+            capabilities.push("sound".to_string());
+        }
+        if self.config.persist_history {
+            capabilities.push("persistence".to_string());
+        }
+        capabilities
     }
 
     pub fn get_server_information(&self) -> Result<(String, String, String, String)> {
@@ -249,8 +787,10 @@ impl NotificationDaemon {
 
     pub async fn open_history(&self) -> Result<()> {
         println!("Getting history");
-        let history = self.notifications_history.read().await;
+        let mut history = self.notifications_history.write().await;
+        // Render before marking the entries read so that the new ones still stand out
         eww_update_and_open_history(&self.config, &history);
+        self.mark_read(&mut history, &HistorySelection::All);
         Ok(())
     }
 
@@ -262,21 +802,18 @@ impl NotificationDaemon {
 
     pub async fn toggle_history(&self) -> Result<()> {
         println!("Toggling history");
-        let history = self.notifications_history.read().await;
+        let mut history = self.notifications_history.write().await;
+        let opening = !eww_is_window_open(&self.config, &self.config.eww_history_window);
         eww_toggle_history(&self.config, &history);
+        if opening {
+            self.mark_read(&mut history, &HistorySelection::All);
+        }
         Ok(())
     }
 
     pub async fn reply_close(&self, id: u32) -> Result<()> {
         println!("Closing reply window");
-        let notifications = self.notifications.try_lock();
-        if let Err(e) = notifications {
-            eprintln!("Failed to lock notifications: {}", e);
-            return Err(zbus::fdo::Error::Failed(
-                "Failed to lock notifications".to_string(),
-            ));
-        }
-        let mut notifications = notifications.unwrap();
+        let mut notifications = self.notifications.lock().await;
         if let Some(notification) = notifications.get_mut(&id) {
             notification.actions.clear();
             eww_update_notifications(&self.config, &notifications);
@@ -311,18 +848,314 @@ impl NotificationDaemon {
 }
 
 impl NotificationDaemon {
-    pub async fn disable_timeout(&self, id: u32) -> Result<()> {
-        let notifications = self.notifications.try_lock();
-        if let Err(e) = notifications {
-            eprintln!("Failed to lock notifications: {}", e);
-            return Err(zbus::fdo::Error::Failed(
-                "Failed to lock notifications".to_string(),
-            ));
+    pub fn shared_state(&self) -> SharedState {
+        SharedState {
+            notifications: Arc::clone(&self.notifications),
+            notifications_history: Arc::clone(&self.notifications_history),
+            config: Arc::clone(&self.config),
+            connection: self.connection.clone(),
         }
-        let mut notifications = notifications.unwrap();
+    }
+
+    pub async fn disable_timeout(&self, id: u32) -> Result<()> {
+        let mut notifications = self.notifications.lock().await;
         if let Some(notification) = notifications.get_mut(&id) {
             notification.timeout_cancelled = true;
         }
         Ok(())
     }
+
+    pub async fn set_group_expanded(&self, app_name: &str, expanded: bool) -> Result<()> {
+        let mut notifications = self.notifications.lock().await;
+        notifications
+            .values_mut()
+            .filter(|notification| notification.app_name == app_name)
+            .for_each(|notification| notification.group_expanded = expanded);
+        eww_update_notifications(&self.config, &notifications);
+        Ok(())
+    }
+
+    /// Removes a popup and emits NotificationClosed with the given reason
+    pub async fn close_notification_with_reason(&self, id: u32, reason: CloseReason) -> Result<()> {
+        let mut notifications = self.notifications.lock().await;
+        if notifications.remove(&id).is_some() {
+            println!("Notification with ID {} closed", id);
+            start_visible_timeouts(&self.shared_state(), &mut notifications);
+            eww_update_notifications(&self.config, &notifications);
+            if notifications.is_empty() {
+                eww_close_notifications(&self.config);
+            }
+            drop(notifications);
+            self.shared_state().notification_closed(id, reason).await;
+        }
+        Ok(())
+    }
+
+    /// Finds the notification ID and sender for invoking `action_key` on the newest history
+    /// entry matching `selection`. Returns None if the entry has no such action or the sender is
+    /// gone.
+    pub async fn history_action_target(
+        &self,
+        selection: &HistorySelection,
+        action_key: &str,
+    ) -> Option<(u32, String)> {
+        let history = self.notifications_history.read().await;
+        let Some((_, entry)) = history
+            .iter()
+            .rev()
+            .enumerate()
+            .find(|(index, entry)| selection.matches(*index, entry))
+        else {
+            eprintln!("No history entry matching {:?}", selection);
+            return None;
+        };
+        if !entry.actions.iter().any(|(key, _)| key == action_key) {
+            eprintln!("Notification {} has no action {}", entry.id, action_key);
+            return None;
+        }
+        let sender = BusName::try_from(entry.sender.as_str()).ok()?;
+        let dbus = zbus::fdo::DBusProxy::new(&self.connection).await.ok()?;
+        if !dbus.name_has_owner(sender).await.unwrap_or(false) {
+            eprintln!("The sender of notification {} is gone", entry.id);
+            return None;
+        }
+        Some((entry.id, entry.sender.clone()))
+    }
+
+    /// Searches the history and formats the matches as a table or JSON. With `show` the history
+    /// window is opened with only the matching entries.
+    pub async fn search_history(&self, filter: &HistoryFilter, json: bool, show: bool) -> String {
+        let history = self.notifications_history.read().await;
+        let matches: Vec<_> = history
+            .iter()
+            .rev()
+            .enumerate()
+            .filter(|(_, entry)| filter.matches(entry))
+            .collect();
+        if show {
+            eww_show_history_entries(&self.config, &matches);
+        }
+        if json {
+            format_json(&matches)
+        } else {
+            format_table(&matches)
+        }
+    }
+
+    /// Removes the selected history entries along with the cached images only they referenced
+    pub async fn remove_history(&self, selection: &HistorySelection) -> Result<()> {
+        let mut history = self.notifications_history.write().await;
+        let len = history.len();
+        let (removed, kept): (Vec<_>, Vec<_>) = history
+            .drain(..)
+            .enumerate()
+            .partition(|(position, entry)| selection.matches(len - 1 - position, entry));
+        *history = kept.into_iter().map(|(_, entry)| entry).collect();
+        println!("Removed {} history entries", removed.len());
+
+        if self.config.persist_history {
+            save_history(&self.config, &history);
+        }
+        eww_update_history(&self.config, &history);
+        drop(history);
+        if !removed.is_empty() {
+            self.shared_state().prune_image_cache().await;
+        }
+        Ok(())
+    }
+
+    pub async fn is_resident(&self, id: u32) -> bool {
+        let notifications = self.notifications.lock().await;
+        notifications
+            .get(&id)
+            .map(|notification| notification.resident)
+            .unwrap_or(false)
+    }
+
+    /// Pauses the timeout of a notification while the pointer is over it and restarts it with
+    /// the time that was left once the pointer leaves
+    pub async fn set_hovered(&self, id: u32, hovered: bool) -> Result<()> {
+        let mut notifications = self.notifications.lock().await;
+        if let Some(notification) = notifications.get_mut(&id) {
+            notification.hovered = hovered;
+            if let Some(hover_guard) = notification.hover_guard.take() {
+                hover_guard.abort();
+            }
+            if hovered {
+                notification.hover_guard = Some(spawn_hover_guard(self.shared_state(), id));
+                if let Some(timeout_future) = notification.timeout_future.take() {
+                    timeout_future.abort();
+                    let remaining = notification
+                        .timeout_deadline
+                        .take()
+                        .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+                        .unwrap_or_default();
+                    // Keep at least a millisecond as 0 means the notification never expires
+                    notification.expire_timeout = (remaining.as_millis() as i32).max(1);
+                    log!("Paused timeout of {} with {:?} left", id, remaining);
+                }
+            } else {
+                start_visible_timeouts(&self.shared_state(), &mut notifications);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_dnd(&mut self, dnd: bool) {
+        println!("Do Not Disturb {}", if dnd { "on" } else { "off" });
+        self.dnd = dnd;
+        eww_update_dnd_state(&self.config, dnd);
+    }
+
+    /// Finds the desktop entry of an app, trying the desktop-entry hint before the app name
+    async fn find_desktop_entry(&mut self, hint: &str, app_name: &str) -> Option<DesktopEntry> {
+        let ids = [
+            hint.to_string(),
+            app_name.to_string(),
+            app_name.to_lowercase(),
+        ];
+        for id in ids.into_iter().filter(|id| !id.is_empty()) {
+            let entry = match self.desktop_entries.get(&id) {
+                Some(entry) => entry.clone(),
+                None => {
+                    // Reading the data dirs blocks, keep it off the runtime like icon lookups
+                    let lookup_id = id.clone();
+                    let entry = tokio::task::spawn_blocking(move || load_desktop_entry(&lookup_id))
+                        .await
+                        .unwrap_or_else(|e| {
+                            log!("Desktop entry lookup failed: {}", e);
+                            None
+                        });
+                    self.desktop_entries.insert(id, entry.clone());
+                    entry
+                }
+            };
+            if entry.is_some() {
+                return entry;
+            }
+        }
+        None
+    }
+
+    /// Counts a new popup of `app_name` and tells whether it goes over `rate_limit`
+    fn rate_limited(&mut self, app_name: &str) -> bool {
+        let limit = &self.config.rate_limit;
+        if limit.count == 0 {
+            return false;
+        }
+        let now = Instant::now();
+        let window = self
+            .rate_windows
+            .entry(app_name.to_string())
+            .or_insert(RateWindow {
+                started: now,
+                count: 0,
+                summary_id: None,
+            });
+        if now.duration_since(window.started) >= Duration::from_secs(limit.interval as u64) {
+            *window = RateWindow {
+                started: now,
+                count: 0,
+                summary_id: None,
+            };
+        }
+        window.count += 1;
+        window.count > limit.count
+    }
+
+    /// Shows or updates the popup standing in for the notifications of an app over the limit
+    async fn show_flood_summary(&mut self, app_name: &str, app_icon: &str) {
+        let mut notifications = self.notifications.lock().await;
+        let window = &self.rate_windows[app_name];
+        let count = window.count;
+        let id = match window
+            .summary_id
+            .filter(|id| notifications.contains_key(id))
+        {
+            Some(id) => id,
+            None => {
+                self.next_id += 1;
+                self.next_id
+            }
+        };
+        self.rate_windows.get_mut(app_name).unwrap().summary_id = Some(id);
+
+        let summary = format!("{} sent {} notifications", app_name, count);
+        let mut notification = Notification {
+            app_name: app_name.to_string(),
+            icon: String::new(),
+            app_icon: app_icon.to_string(),
+            summary,
+            body: String::new(),
+            body_markup: String::new(),
+            urgency: String::from("normal"),
+            progress: None,
+            actions: Vec::new(),
+            timeout_cancelled: false,
+            timeout_future: None,
+            expire_timeout: self.config.timeout.normal as i32 * 1000,
+            timeout_deadline: None,
+            hovered: false,
+            hover_guard: None,
+            resident: false,
+            window: None,
+            widget: None,
+            group_expanded: false,
+            count: 1,
+            desktop_entry: String::new(),
+        };
+        if let Some(replaced) = notifications.remove(&id) {
+            if let Some(timeout_future) = replaced.timeout_future {
+                timeout_future.abort();
+            }
+            notification.hovered = replaced.hovered;
+            notification.hover_guard = replaced.hover_guard;
+            notification.group_expanded = replaced.group_expanded;
+        }
+        notifications.insert(id, notification);
+        start_visible_timeouts(&self.shared_state(), &mut notifications);
+        eww_update_notifications(&self.config, &notifications);
+    }
+
+    /// Marks the selected history entries as read. Returns whether any entry was unread.
+    fn mark_read(&self, history: &mut [HistoryNotification], selection: &HistorySelection) -> bool {
+        let len = history.len();
+        let mut changed = false;
+        for (position, entry) in history.iter_mut().enumerate() {
+            if !entry.read && selection.matches(len - 1 - position, entry) {
+                entry.read = true;
+                changed = true;
+            }
+        }
+        if changed {
+            if self.config.persist_history {
+                save_history(&self.config, history);
+            }
+            eww_update_history_state(&self.config, history);
+        }
+        changed
+    }
+
+    /// Marks history entries as read from `end-rs history mark-read`
+    pub async fn mark_history_read(&self, selection: &HistorySelection) -> Result<()> {
+        let mut history = self.notifications_history.write().await;
+        if self.mark_read(&mut history, selection) {
+            eww_update_history(&self.config, &history);
+        }
+        Ok(())
+    }
+
+    /// Summary printed by `end-rs status`
+    pub async fn status(&self) -> String {
+        let active = self.notifications.lock().await.len();
+        let history = self.notifications_history.read().await;
+        let unread = history.iter().filter(|entry| !entry.read).count();
+        format!(
+            "active: {}\nhistory: {}\nunread: {}\ndnd: {}",
+            active,
+            history.len(),
+            unread,
+            if self.dnd { "on" } else { "off" }
+        )
+    }
 }
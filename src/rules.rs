@@ -0,0 +1,274 @@
+use regex::Regex;
+
+use crate::config::Rule;
+use crate::log;
+
+/// The parts of an incoming notification the rules can match on
+pub struct RuleSubject<'a> {
+    /// The name shown, after desktop entries and `[apps]` overrides
+    pub app_name: &'a str,
+    /// The name the application sent
+    pub sent_app_name: &'a str,
+    pub summary: &'a str,
+    pub body: &'a str,
+    pub urgency: &'a str,
+    pub category: &'a str,
+    pub desktop_entry: &'a str,
+}
+
+/// Combined effect of all the rules matching a notification
+#[derive(Default, Debug)]
+pub struct RuleOutcome {
+    pub urgency: Option<u8>,
+    pub timeout: Option<u32>,
+    pub suppress_popup: bool,
+    pub skip_history: bool,
+    pub transient: bool,
+    pub window: Option<String>,
+    pub widget: Option<String>,
+}
+
+pub fn urgency_from_str(urgency: &str) -> Option<u8> {
+    match urgency {
+        "low" => Some(0),
+        "normal" => Some(1),
+        "critical" => Some(2),
+        _ => None,
+    }
+}
+
+/// A rule with its regexes compiled, built once when the daemon starts
+pub struct CompiledRule {
+    pub rule: Rule,
+    app_name: Option<Regex>,
+    summary: Option<Regex>,
+    body: Option<Regex>,
+}
+
+/// Compiles the regexes of every rule. Fails on the first invalid pattern, naming the rule.
+pub fn compile_rules(rules: &[Rule]) -> Result<Vec<CompiledRule>, String> {
+    let compile = |index: usize, pattern: &Option<String>| {
+        pattern
+            .as_ref()
+            .map(|pattern| Regex::new(pattern))
+            .transpose()
+            .map_err(|e| format!("Invalid regex in rule {}: {}", index + 1, e))
+    };
+    rules
+        .iter()
+        .enumerate()
+        .map(|(index, rule)| {
+            Ok(CompiledRule {
+                rule: rule.clone(),
+                app_name: compile(index, &rule.app_name)?,
+                summary: compile(index, &rule.summary)?,
+                body: compile(index, &rule.body)?,
+            })
+        })
+        .collect()
+}
+
+fn regex_matches(regex: &Option<Regex>, text: &str) -> bool {
+    regex.as_ref().is_none_or(|regex| regex.is_match(text))
+}
+
+fn exact_matches(expected: &Option<String>, text: &str) -> bool {
+    match expected {
+        None => true,
+        Some(expected) => expected == text,
+    }
+}
+
+impl CompiledRule {
+    pub fn matches(&self, subject: &RuleSubject) -> bool {
+        (regex_matches(&self.app_name, subject.app_name)
+            || regex_matches(&self.app_name, subject.sent_app_name))
+            && regex_matches(&self.summary, subject.summary)
+            && regex_matches(&self.body, subject.body)
+            && exact_matches(&self.rule.urgency, subject.urgency)
+            && exact_matches(&self.rule.category, subject.category)
+            && exact_matches(&self.rule.desktop_entry, subject.desktop_entry)
+    }
+}
+
+/// Applies every matching rule in the order they appear in the config. Later rules override
+/// the urgency, timeout, window and widget set by earlier ones.
+pub fn evaluate_rules(rules: &[CompiledRule], subject: &RuleSubject) -> RuleOutcome {
+    let mut outcome = RuleOutcome::default();
+    for rule in rules.iter().filter(|rule| rule.matches(subject)) {
+        let rule = &rule.rule;
+        log!("Rule matched: {:?}", rule);
+        if let Some(urgency) = &rule.set_urgency {
+            match urgency_from_str(urgency) {
+                Some(urgency) => outcome.urgency = Some(urgency),
+                None => log!("Invalid urgency in rule: {}", urgency),
+            }
+        }
+        if rule.timeout.is_some() {
+            outcome.timeout = rule.timeout;
+        }
+        if rule.window.is_some() {
+            outcome.window = rule.window.clone();
+        }
+        if rule.widget.is_some() {
+            outcome.widget = rule.widget.clone();
+        }
+        outcome.suppress_popup |= rule.suppress_popup;
+        outcome.skip_history |= rule.skip_history;
+        outcome.transient |= rule.transient;
+    }
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subject<'a>(app_name: &'a str, summary: &'a str) -> RuleSubject<'a> {
+        RuleSubject {
+            app_name,
+            sent_app_name: app_name,
+            summary,
+            body: "Body text",
+            urgency: "normal",
+            category: "im.received",
+            desktop_entry: "org.example.App",
+        }
+    }
+
+    fn evaluate(rules: Vec<Rule>, subject: &RuleSubject) -> RuleOutcome {
+        evaluate_rules(&compile_rules(&rules).unwrap(), subject)
+    }
+
+    #[test]
+    fn rejects_invalid_regex() {
+        let rules = vec![
+            Rule::default(),
+            Rule {
+                summary: Some("(unclosed".to_string()),
+                ..Default::default()
+            },
+        ];
+        let error = compile_rules(&rules).err().unwrap();
+        assert!(error.starts_with("Invalid regex in rule 2:"), "{}", error);
+    }
+
+    #[test]
+    fn matches_every_condition() {
+        let rule = |rule: Rule| compile_rules(&[rule]).unwrap().remove(0);
+        let subject = subject("Signal", "New message");
+        assert!(rule(Rule::default()).matches(&subject));
+        assert!(rule(Rule {
+            app_name: Some("^Sig".to_string()),
+            summary: Some("message$".to_string()),
+            body: Some("text".to_string()),
+            urgency: Some("normal".to_string()),
+            category: Some("im.received".to_string()),
+            desktop_entry: Some("org.example.App".to_string()),
+            ..Default::default()
+        })
+        .matches(&subject));
+        assert!(!rule(Rule {
+            app_name: Some("^Sig".to_string()),
+            urgency: Some("critical".to_string()),
+            ..Default::default()
+        })
+        .matches(&subject));
+        // Urgency, category and desktop entry are compared exactly
+        assert!(!rule(Rule {
+            category: Some("im".to_string()),
+            ..Default::default()
+        })
+        .matches(&subject));
+    }
+
+    #[test]
+    fn matches_sent_and_shown_app_name() {
+        let rule = compile_rules(&[Rule {
+            app_name: Some("^firefox$".to_string()),
+            ..Default::default()
+        }])
+        .unwrap()
+        .remove(0);
+        let mut subject = subject("Firefox", "Download finished");
+        assert!(!rule.matches(&subject));
+        subject.sent_app_name = "firefox";
+        assert!(rule.matches(&subject));
+        subject.app_name = "firefox";
+        subject.sent_app_name = "Firefox";
+        assert!(rule.matches(&subject));
+    }
+
+    #[test]
+    fn later_rules_override_earlier_ones() {
+        let outcome = evaluate(
+            vec![
+                Rule {
+                    set_urgency: Some("critical".to_string()),
+                    timeout: Some(5),
+                    window: Some("first".to_string()),
+                    widget: Some("first".to_string()),
+                    ..Default::default()
+                },
+                Rule {
+                    set_urgency: Some("low".to_string()),
+                    window: Some("second".to_string()),
+                    ..Default::default()
+                },
+                Rule {
+                    summary: Some("no match".to_string()),
+                    timeout: Some(30),
+                    ..Default::default()
+                },
+            ],
+            &subject("Signal", "New message"),
+        );
+        assert_eq!(outcome.urgency, Some(0));
+        assert_eq!(outcome.timeout, Some(5));
+        assert_eq!(outcome.window.as_deref(), Some("second"));
+        assert_eq!(outcome.widget.as_deref(), Some("first"));
+    }
+
+    #[test]
+    fn ignores_invalid_urgency() {
+        let outcome = evaluate(
+            vec![
+                Rule {
+                    set_urgency: Some("critical".to_string()),
+                    ..Default::default()
+                },
+                Rule {
+                    set_urgency: Some("urgent".to_string()),
+                    ..Default::default()
+                },
+            ],
+            &subject("Signal", "New message"),
+        );
+        assert_eq!(outcome.urgency, Some(2));
+    }
+
+    #[test]
+    fn ors_flags() {
+        let outcome = evaluate(
+            vec![
+                Rule {
+                    suppress_popup: true,
+                    ..Default::default()
+                },
+                Rule {
+                    skip_history: true,
+                    ..Default::default()
+                },
+                Rule {
+                    transient: true,
+                    summary: Some("no match".to_string()),
+                    ..Default::default()
+                },
+            ],
+            &subject("Signal", "New message"),
+        );
+        assert!(outcome.suppress_popup);
+        assert!(outcome.skip_history);
+        assert!(!outcome.transient);
+    }
+}
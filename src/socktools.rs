@@ -2,17 +2,22 @@
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::mpsc;
-use zbus::conn::Builder;
+use tokio::sync::{mpsc, oneshot, RwLock};
 use zbus::fdo::Result;
 use zbus::Connection;
 
 use crate::config::Config;
-use crate::ewwface::{eww_create_reply_widget, eww_open_window, eww_update_value};
+use crate::ewwface::{
+    eww_create_reply_widget, eww_open_window, eww_update_dnd_state, eww_update_history_state,
+    eww_update_notification_state, eww_update_value,
+};
+use crate::history::{HistoryFilter, HistorySelection};
 use crate::log;
-use crate::notifdaemon::NotificationDaemon;
+use crate::notifdaemon::{CloseReason, NotificationDaemon};
+use crate::rules::compile_rules;
+use crate::utils::{load_history, IconCache};
 
 #[derive(Serialize, Deserialize)]
 enum DaemonActions {
@@ -23,18 +28,59 @@ enum DaemonActions {
     ActionInvoked(u32, String),
     ReplySend(u32, String),
     ReplyClose(u32),
+    SetDnd(bool),
+    ToggleDnd,
+    DndStatus,
+    ExpandGroup(String),
+    CollapseGroup(String),
+    HoverEnter(u32),
+    HoverLeave(u32),
+    HistoryAction(HistorySelection, String),
+    RemoveHistory(HistorySelection),
+    MarkHistoryRead(HistorySelection),
+    Status,
+    SearchHistory {
+        filter: HistoryFilter,
+        json: bool,
+        show: bool,
+    },
 }
 
-async fn handle_connection(stream: UnixStream, tx: mpsc::Sender<String>) {
-    let mut reader = BufReader::new(stream);
+impl DaemonActions {
+    /// Whether the client should wait for the daemon to answer this action
+    fn expects_reply(&self) -> bool {
+        matches!(
+            self,
+            DaemonActions::DndStatus | DaemonActions::Status | DaemonActions::SearchHistory { .. }
+        )
+    }
+}
+
+type DaemonMessage = (String, oneshot::Sender<String>);
+
+async fn handle_connection(stream: UnixStream, tx: mpsc::Sender<DaemonMessage>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
     let mut line = String::new();
     while reader.read_line(&mut line).await.unwrap() > 0 {
-        tx.send(line.clone()).await.unwrap();
+        let (reply_tx, reply_rx) = oneshot::channel();
+        tx.send((line.clone(), reply_tx)).await.unwrap();
         line.clear();
+        if let Ok(reply) = reply_rx.await {
+            if !reply.is_empty() {
+                let _ = writer.write_all(reply.as_bytes()).await;
+            }
+        }
     }
 }
 
 pub async fn run_daemon(cfg: Config) -> Result<()> {
+    // Report broken rules before taking over the socket of a running daemon
+    let rules = compile_rules(&cfg.rules).map_err(|e| {
+        eprintln!("{}", e);
+        zbus::fdo::Error::Failed(e)
+    })?;
+
     let path = "/tmp/rust_ipc_socket";
     if Path::new(path).exists() {
         std::fs::remove_file(path).map_err(|e| {
@@ -48,42 +94,62 @@ pub async fn run_daemon(cfg: Config) -> Result<()> {
         zbus::fdo::Error::Failed("Failed to bind to socket".to_string())
     })?;
 
-    let (tx, mut rx) = mpsc::channel::<String>(100);
+    let (tx, mut rx) = mpsc::channel::<DaemonMessage>(100);
     let cfg = Arc::new(cfg);
 
     // Initialize daemon-specific structures
-    let connection = Connection::session().await?;
+    let conn = Connection::session().await?;
+    let history = if cfg.persist_history {
+        load_history(&cfg)
+    } else {
+        Vec::new()
+    };
+    // Don't hand out IDs of persisted entries again
+    let next_id = history.iter().map(|entry| entry.id).max().unwrap_or(0);
+    // Start the state variables from what the daemon knows instead of what eww was left with
+    eww_update_notification_state(&cfg, &Default::default());
+    eww_update_history_state(&cfg, &history);
+    eww_update_dnd_state(&cfg, false);
     let daemon = NotificationDaemon {
         notifications: Default::default(),
-        notifications_history: Default::default(),
+        notifications_history: Arc::new(RwLock::new(history)),
         config: Arc::clone(&cfg),
-        next_id: 0,
-        connection,
+        next_id,
+        // Signals have to come from the connection owning the name, clients filter on it
+        connection: conn.clone(),
+        dnd: false,
+        rate_windows: Default::default(),
+        icon_cache: IconCache::new(&cfg),
+        rules,
+        desktop_entries: Default::default(),
     };
 
-    let conn = Builder::session()?
-        .name("org.freedesktop.Notifications")?
-        .serve_at("/org/freedesktop/Notifications", daemon)?
-        .build()
+    conn.object_server()
+        .at("/org/freedesktop/Notifications", daemon)
         .await?;
+    conn.request_name("org.freedesktop.Notifications").await?;
 
     tokio::spawn(async move {
-        while let Some(message) = rx.recv().await {
+        while let Some((message, reply)) = rx.recv().await {
             let iface_ref = conn
                 .object_server()
                 .interface::<_, NotificationDaemon>("/org/freedesktop/Notifications")
                 .await
                 .unwrap();
 
-            let iface = iface_ref.get_mut().await;
+            let mut iface = iface_ref.get_mut().await;
             println!("Received: {}", message);
             let message: DaemonActions = serde_json::from_str(&message).unwrap();
             let dest: Option<&str> = None;
+            let mut response = String::new();
 
             match message {
                 DaemonActions::CloseNotification(id) => {
                     log!("Closing notification {}", id);
-                    iface.close_notification(id).await.unwrap();
+                    iface
+                        .close_notification_with_reason(id, CloseReason::Dismissed)
+                        .await
+                        .unwrap();
                     log!("Notification {} closed", id);
                 }
                 DaemonActions::OpenHistory => {
@@ -110,7 +176,9 @@ pub async fn run_daemon(cfg: Config) -> Result<()> {
                         eww_update_value(&cfg, &cfg.eww_reply_text, "");
                         eww_update_value(&cfg, &cfg.eww_reply_var, eww_widget_str);
                         let _ = eww_open_window(&cfg, &cfg.eww_reply_window);
-                        iface.disable_timeout(id).await.unwrap();
+                        if let Err(e) = iface.disable_timeout(id).await {
+                            log!("Failed to disable the timeout of {}: {}", id, e);
+                        }
                         log!("Inline reply for notification {} opened", id);
                     } else {
                         log!("Invoking action {} for notification {}", action, id);
@@ -123,15 +191,12 @@ pub async fn run_daemon(cfg: Config) -> Result<()> {
                         )
                         .await
                         .unwrap();
-                        conn.emit_signal(
-                            dest,
-                            "/org/freedesktop/Notifications",
-                            "org.freedesktop.Notifications",
-                            "NotificationClosed",
-                            &(id, 3_u32),
-                        )
-                        .await
-                        .unwrap();
+                        if !iface.is_resident(id).await {
+                            iface
+                                .close_notification_with_reason(id, CloseReason::Dismissed)
+                                .await
+                                .unwrap();
+                        }
                         log!("Invoked action {} for notification {}", action, id);
                     }
                 }
@@ -147,8 +212,13 @@ pub async fn run_daemon(cfg: Config) -> Result<()> {
                     )
                     .await
                     .unwrap();
-                    iface.reply_close(id).await.unwrap();
-                    iface.close_notification(id).await.unwrap();
+                    if let Err(e) = iface.reply_close(id).await {
+                        log!("Failed to close the reply of {}: {}", id, e);
+                    }
+                    iface
+                        .close_notification_with_reason(id, CloseReason::Dismissed)
+                        .await
+                        .unwrap();
                     log!("Sent reply {} for notification {}", reply, id);
                 }
                 DaemonActions::ReplyClose(id) => {
@@ -163,10 +233,86 @@ pub async fn run_daemon(cfg: Config) -> Result<()> {
                     )
                     .await
                     .unwrap();
-                    iface.reply_close(id).await.unwrap();
+                    if let Err(e) = iface.reply_close(id).await {
+                        log!("Failed to close the reply of {}: {}", id, e);
+                    }
                     log!("Closed reply for notification {}", id);
                 }
+                DaemonActions::SetDnd(dnd) => {
+                    log!("Setting Do Not Disturb to {}", dnd);
+                    iface.set_dnd(dnd);
+                }
+                DaemonActions::ToggleDnd => {
+                    log!("Toggling Do Not Disturb");
+                    let dnd = !iface.dnd;
+                    iface.set_dnd(dnd);
+                }
+                DaemonActions::DndStatus => {
+                    response = if iface.dnd { "on" } else { "off" }.to_string();
+                }
+                DaemonActions::ExpandGroup(app_name) => {
+                    log!("Expanding group {}", app_name);
+                    if let Err(e) = iface.set_group_expanded(&app_name, true).await {
+                        log!("Failed to expand group {}: {}", app_name, e);
+                    }
+                }
+                DaemonActions::CollapseGroup(app_name) => {
+                    log!("Collapsing group {}", app_name);
+                    if let Err(e) = iface.set_group_expanded(&app_name, false).await {
+                        log!("Failed to collapse group {}: {}", app_name, e);
+                    }
+                }
+                DaemonActions::HistoryAction(selection, action) => {
+                    log!(
+                        "Invoking action {} for history entry {:?}",
+                        action,
+                        selection
+                    );
+                    if let Some((id, sender)) =
+                        iface.history_action_target(&selection, &action).await
+                    {
+                        conn.emit_signal(
+                            Some(sender.as_str()),
+                            "/org/freedesktop/Notifications",
+                            "org.freedesktop.Notifications",
+                            "ActionInvoked",
+                            &(id, &action),
+                        )
+                        .await
+                        .unwrap();
+                        log!("Invoked action {} for notification {}", action, id);
+                    }
+                }
+                DaemonActions::RemoveHistory(selection) => {
+                    log!("Removing history entries {:?}", selection);
+                    iface.remove_history(&selection).await.unwrap();
+                    log!("Removed history entries {:?}", selection);
+                }
+                DaemonActions::MarkHistoryRead(selection) => {
+                    log!("Marking history entries {:?} as read", selection);
+                    iface.mark_history_read(&selection).await.unwrap();
+                }
+                DaemonActions::Status => {
+                    response = iface.status().await;
+                }
+                DaemonActions::SearchHistory { filter, json, show } => {
+                    log!("Searching history for {:?}", filter);
+                    response = iface.search_history(&filter, json, show).await;
+                }
+                DaemonActions::HoverEnter(id) => {
+                    log!("Pointer entered notification {}", id);
+                    if let Err(e) = iface.set_hovered(id, true).await {
+                        log!("Failed to pause the timeout of {}: {}", id, e);
+                    }
+                }
+                DaemonActions::HoverLeave(id) => {
+                    log!("Pointer left notification {}", id);
+                    if let Err(e) = iface.set_hovered(id, false).await {
+                        log!("Failed to resume the timeout of {}: {}", id, e);
+                    }
+                }
             };
+            let _ = reply.send(response);
         }
     });
 
@@ -182,7 +328,8 @@ pub async fn run_daemon(cfg: Config) -> Result<()> {
     }
 }
 
-pub async fn send_message(args: Vec<String>) -> Result<()> {
+/// Sends a command to the daemon. Returns the daemon's answer for commands that query it.
+pub async fn send_message(args: Vec<String>) -> Result<Option<String>> {
     let path = "/tmp/rust_ipc_socket";
 
     if let Ok(mut stream) = UnixStream::connect(path).await {
@@ -212,6 +359,77 @@ pub async fn send_message(args: Vec<String>) -> Result<()> {
                     "open" => DaemonActions::OpenHistory,
                     "close" => DaemonActions::CloseHistory,
                     "toggle" => DaemonActions::ToggleHistory,
+                    "clear" => match (args.get(2).map(|arg| arg.as_str()), args.get(3)) {
+                        (None, _) => DaemonActions::RemoveHistory(HistorySelection::All),
+                        (Some("--app"), Some(app)) => {
+                            DaemonActions::RemoveHistory(HistorySelection::App(app.clone()))
+                        }
+                        _ => {
+                            return Err(zbus::fdo::Error::Failed(
+                                "Invalid command to history-clear".to_string(),
+                            ));
+                        }
+                    },
+                    "remove" => match (args.get(2).map(|arg| arg.as_str()), args.get(3)) {
+                        (Some("--id"), Some(id)) => DaemonActions::RemoveHistory(
+                            HistorySelection::Id(id.parse::<u32>().unwrap()),
+                        ),
+                        (Some(index), None) => DaemonActions::RemoveHistory(
+                            HistorySelection::Index(index.parse::<usize>().unwrap()),
+                        ),
+                        _ => {
+                            return Err(zbus::fdo::Error::Failed(
+                                "Invalid command to history-remove".to_string(),
+                            ));
+                        }
+                    },
+                    "mark-read" => match args.get(2) {
+                        None => DaemonActions::MarkHistoryRead(HistorySelection::All),
+                        Some(id) => DaemonActions::MarkHistoryRead(HistorySelection::Id(
+                            id.parse::<u32>().unwrap(),
+                        )),
+                    },
+                    "search" => {
+                        let mut json = false;
+                        let mut show = false;
+                        let search_args: Vec<String> = args[2..]
+                            .iter()
+                            .filter(|arg| match arg.as_str() {
+                                "--json" => {
+                                    json = true;
+                                    false
+                                }
+                                "--show" => {
+                                    show = true;
+                                    false
+                                }
+                                _ => true,
+                            })
+                            .cloned()
+                            .collect();
+                        let filter = HistoryFilter::from_args(&search_args)
+                            .map_err(zbus::fdo::Error::Failed)?;
+                        DaemonActions::SearchHistory { filter, json, show }
+                    }
+                    "action" => match (
+                        args.get(2).map(|arg| arg.as_str()),
+                        args.get(3),
+                        args.get(4),
+                    ) {
+                        (Some("--id"), Some(id), Some(action)) => DaemonActions::HistoryAction(
+                            HistorySelection::Id(id.parse::<u32>().unwrap()),
+                            action.to_string(),
+                        ),
+                        (Some(index), Some(action), None) => DaemonActions::HistoryAction(
+                            HistorySelection::Index(index.parse::<usize>().unwrap()),
+                            action.to_string(),
+                        ),
+                        _ => {
+                            return Err(zbus::fdo::Error::Failed(
+                                "Invalid command to history-action".to_string(),
+                            ));
+                        }
+                    },
                     _ => {
                         return Err(zbus::fdo::Error::Failed("Invalid command".to_string()));
                     }
@@ -225,6 +443,52 @@ pub async fn send_message(args: Vec<String>) -> Result<()> {
                 }
                 DaemonActions::ActionInvoked(args[1].parse::<u32>().unwrap(), args[2].to_string())
             }
+            "dnd" => {
+                if args.len() < 2 {
+                    return Err(zbus::fdo::Error::Failed(
+                        "Invalid command to dnd".to_string(),
+                    ));
+                }
+                match args[1].as_str() {
+                    "on" => DaemonActions::SetDnd(true),
+                    "off" => DaemonActions::SetDnd(false),
+                    "toggle" => DaemonActions::ToggleDnd,
+                    "status" => DaemonActions::DndStatus,
+                    _ => {
+                        return Err(zbus::fdo::Error::Failed("Invalid command".to_string()));
+                    }
+                }
+            }
+            "status" => DaemonActions::Status,
+            "group" => {
+                if args.len() < 3 {
+                    return Err(zbus::fdo::Error::Failed(
+                        "Invalid command to group".to_string(),
+                    ));
+                }
+                match args[1].as_str() {
+                    "expand" => DaemonActions::ExpandGroup(args[2].clone()),
+                    "collapse" => DaemonActions::CollapseGroup(args[2].clone()),
+                    _ => {
+                        return Err(zbus::fdo::Error::Failed("Invalid command".to_string()));
+                    }
+                }
+            }
+            "hover" => {
+                if args.len() < 3 {
+                    return Err(zbus::fdo::Error::Failed(
+                        "Invalid command to hover".to_string(),
+                    ));
+                }
+                let id = args[1].parse::<u32>().unwrap();
+                match args[2].as_str() {
+                    "enter" => DaemonActions::HoverEnter(id),
+                    "leave" => DaemonActions::HoverLeave(id),
+                    _ => {
+                        return Err(zbus::fdo::Error::Failed("Invalid command".to_string()));
+                    }
+                }
+            }
             "reply" => {
                 if args.len() < 3 {
                     return Err(zbus::fdo::Error::Failed(
@@ -252,18 +516,34 @@ pub async fn send_message(args: Vec<String>) -> Result<()> {
             }
         };
 
+        let expects_reply = message.expects_reply();
         let message = serde_json::to_string(&message).map_err(|e| {
             eprintln!("Failed to serialize message: {}", e);
             zbus::fdo::Error::Failed("Failed to serialize message".to_string())
         })?;
 
-        println!("Sending message {:?}", message);
+        if !expects_reply {
+            println!("Sending message {:?}", message);
+        }
         let message = message.as_bytes();
 
         stream.write_all(message).await.map_err(|e| {
             eprintln!("Failed to write to stream: {}", e);
             zbus::fdo::Error::Failed("Failed to write to stream".to_string())
         })?;
+
+        if expects_reply {
+            stream.shutdown().await.map_err(|e| {
+                eprintln!("Failed to shutdown stream: {}", e);
+                zbus::fdo::Error::Failed("Failed to shutdown stream".to_string())
+            })?;
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).await.map_err(|e| {
+                eprintln!("Failed to read from stream: {}", e);
+                zbus::fdo::Error::Failed("Failed to read from stream".to_string())
+            })?;
+            return Ok(Some(reply));
+        }
         println!("Message sent");
     } else {
         eprintln!("Failed to connect to the daemon.");
@@ -271,5 +551,5 @@ pub async fn send_message(args: Vec<String>) -> Result<()> {
 
     println!("Exiting");
 
-    Ok(())
+    Ok(None)
 }
@@ -1,126 +1,508 @@
 use icon_loader::IconLoader;
-use std::{fs, io::Write, path::Path};
+use std::collections::HashSet;
+use std::time::SystemTime;
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
 use zvariant::{Structure, Value};
 
 use crate::config::Config;
 use crate::log;
+use crate::markup::parse_body;
+use crate::notifdaemon::HistoryNotification;
 
-use std::sync::{mpsc, Arc};
-use std::thread;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-pub fn find_icon(icon_name: &str, config: &Config) -> Option<String> {
-    // Check whether the icon needs to be searched
-    log!("Icon name: {}", icon_name);
-    let mut loader = IconLoader::new();
-    log!("Created IconLoader");
-    loader.set_search_paths(&config.icon_dirs);
-    loader.set_theme_name_provider(config.icon_theme.clone());
-    let loader = match loader.update_theme_name() {
-        Ok(_) => loader,
-        Err(_) => return None,
+/// Turns a `file://` URI into a path, decoding percent-encoded bytes. Anything else is returned
+/// unchanged.
+fn file_uri_to_path(uri: &str) -> String {
+    let path = match uri.strip_prefix("file://") {
+        Some(path) => path,
+        None => return uri.to_string(),
     };
-    let loader = Arc::new(loader);
-    log!("Updated IconLoader");
-
-    if icon_name.is_empty() {
-        log!("Empty icon name");
-        None
-    } else if icon_name.starts_with('/') {
-        log!("Found icon: {:?}", icon_name);
-        Some(icon_name.to_string())
-    } else if icon_name.starts_with('~') {
-        log!("Found icon: {:?}", icon_name);
-        Some(icon_name.replace('~', format!("{}/", std::env::var("HOME").unwrap()).as_str()))
-    } else {
-        // I believe that IconLoader sometimes locks up if there is no icon for it to look up for.
-        // Hence we shove it into a thread and wait it out. In case it locks up, the daemon still
-        // keeps running
-
-        log!("Searching for icon: {}", icon_name);
-
-        // Use a channel to communicate between the threads
-        let (tx, rx) = mpsc::channel();
-
-        // Spawn a new thread to execute the potentially blocking code
-        let icon_name = icon_name.to_string(); // Clone `icon_name` to move it into the thread
-        let loader_clone = loader.clone(); // Clone `loader` to move it into the thread
-        thread::spawn(move || {
-            let icon = loader_clone.load_icon(&icon_name);
-            // Send the result back to the main thread
-            let _ = tx.send(icon);
-        });
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// How long the icon lookups of a notification may take together. IconLoader sometimes locks up
+/// if there is no icon for it to look up, a lookup taking longer counts as a miss.
+pub const ICON_LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Size the icons are looked up in
+pub const ICON_SIZE: u16 = 64;
 
-        // Set a timeout for the operation
-        match rx.recv_timeout(Duration::from_secs(2)) {
-            // adjust duration as needed
-            Ok(Some(icon)) => {
-                log!("Loaded icon");
-                let icon_path = icon.file_for_size(64).path().to_str().unwrap().to_string();
+/// Found icon paths, or None for misses, by icon name and size
+type IconPaths = HashMap<(String, u16), Option<String>>;
+
+/// Icon lookups sharing a single IconLoader, with the results (misses included) cached per name
+/// and size for the lifetime of the daemon
+pub struct IconCache {
+    loader: Option<Arc<IconLoader>>,
+    cache: Arc<Mutex<IconPaths>>,
+}
+
+impl IconCache {
+    pub fn new(config: &Config) -> IconCache {
+        let mut loader = IconLoader::new();
+        loader.set_search_paths(&config.icon_dirs);
+        loader.set_theme_name_provider(config.icon_theme.clone());
+        let loader = match loader.update_theme_name() {
+            Ok(_) => Some(Arc::new(loader)),
+            Err(e) => {
+                log!("Failed to load icon theme {}: {}", config.icon_theme, e);
+                None
+            }
+        };
+        IconCache {
+            loader,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Looks up an icon, giving up at `deadline`. The lookups of one notification share a
+    /// deadline so that a broken theme can't hold it up once per icon.
+    pub async fn find_icon(
+        &self,
+        icon_name: &str,
+        size: u16,
+        deadline: tokio::time::Instant,
+    ) -> Option<String> {
+        // Check whether the icon needs to be searched
+        log!("Icon name: {}", icon_name);
+        let icon_name = file_uri_to_path(icon_name);
+        if icon_name.is_empty() {
+            log!("Empty icon name");
+            return None;
+        } else if icon_name.starts_with('/') {
+            log!("Found icon: {:?}", icon_name);
+            return Some(icon_name);
+        } else if icon_name.starts_with('~') {
+            log!("Found icon: {:?}", icon_name);
+            return Some(
+                icon_name.replace('~', format!("{}/", std::env::var("HOME").unwrap()).as_str()),
+            );
+        }
+
+        let key = (icon_name, size);
+        if let Some(icon_path) = self.cache.lock().unwrap().get(&key) {
+            log!("Cached icon: {:?}", icon_path);
+            return icon_path.clone();
+        }
+        let loader = self.loader.clone()?;
+
+        log!("Searching for icon: {}", key.0);
+        let lookup_key = key.clone();
+        let cache = self.cache.clone();
+        let lookup = tokio::task::spawn_blocking(move || {
+            let icon_path = loader.load_icon(&lookup_key.0).and_then(|icon| {
+                icon.file_for_size(size)
+                    .path()
+                    .to_str()
+                    .map(|path| path.to_string())
+            });
+            // Also stored when the lookup finishes after the deadline, replacing the miss
+            cache.lock().unwrap().insert(lookup_key, icon_path.clone());
+            icon_path
+        });
+        match tokio::time::timeout_at(deadline, lookup).await {
+            Ok(Ok(Some(icon_path))) => {
                 log!("Found icon: {:?}", icon_path);
                 Some(icon_path)
             }
-            Ok(None) => {
+            Ok(Ok(None)) => {
                 log!("No icon found");
                 None
             }
+            Ok(Err(e)) => {
+                log!("Icon lookup failed: {}", e);
+                None
+            }
             Err(_) => {
-                // If the thread doesn't respond in time (i.e., it locked up), log an error and return None
+                // The blocking thread keeps running, but the notification doesn't wait for it
                 log!("Icon loading timed out");
+                self.cache.lock().unwrap().entry(key).or_insert(None);
                 None
             }
         }
     }
 }
 
-pub fn save_icon(icon_data: &Structure, id: u32) -> Option<String> {
-    let parent_dir = "/tmp/end-data";
-    if !Path::new(&parent_dir).exists() {
-        fs::create_dir_all(parent_dir).unwrap();
-    }
-    let icon_path = format!("{}/{}.png", parent_dir, id);
-    let width: Result<i32, zvariant::Error> = icon_data.fields()[0].try_clone().unwrap().try_into();
-    let height: Result<i32, zvariant::Error> =
-        icon_data.fields()[1].try_clone().unwrap().try_into();
-    let _rowstride: Result<i32, zvariant::Error> =
-        icon_data.fields()[2].try_clone().unwrap().try_into();
-    let _has_alpha: Result<bool, zvariant::Error> =
-        icon_data.fields()[3].try_clone().unwrap().try_into();
-    let _bits_per_sample: Result<i32, zvariant::Error> =
-        icon_data.fields()[4].try_clone().unwrap().try_into();
-    let _channels: Result<i32, zvariant::Error> =
-        icon_data.fields()[5].try_clone().unwrap().try_into();
-    let data = icon_data.fields()[6].try_clone();
-    let mut vec_val: Vec<u8> = Vec::new();
-    if width.is_err() || height.is_err() || data.is_err() {
+/// `$XDG_DATA_HOME` followed by `$XDG_DATA_DIRS`, in the order they should be searched
+pub fn xdg_data_dirs() -> Vec<String> {
+    let home = std::env::var("HOME").unwrap_or_default();
+    let mut data_dirs =
+        vec![std::env::var("XDG_DATA_HOME").unwrap_or_else(|_| format!("{}/.local/share", home))];
+    data_dirs.extend(
+        std::env::var("XDG_DATA_DIRS")
+            .unwrap_or_else(|_| String::from("/usr/local/share:/usr/share"))
+            .split(':')
+            .map(|dir| dir.to_string()),
+    );
+    data_dirs
+}
+
+/// Looks up a sound by its name in the XDG sound theme directories, falling back to the
+/// freedesktop theme
+pub fn find_sound(sound_name: &str, config: &Config) -> Option<String> {
+    log!("Sound name: {}", sound_name);
+    if sound_name.is_empty() {
         return None;
+    } else if sound_name.starts_with('/') {
+        return Some(sound_name.to_string());
+    } else if sound_name.starts_with('~') {
+        return Some(sound_name.replace('~', std::env::var("HOME").unwrap().as_str()));
+    }
+
+    let data_dirs = xdg_data_dirs();
+    let mut themes = vec![config.sound_theme.as_str()];
+    if config.sound_theme != "freedesktop" {
+        themes.push("freedesktop");
     }
-    let width = width.unwrap();
-    let height = height.unwrap();
-    let data = data.unwrap();
-    match data {
-        Value::Array(data) => {
-            data.iter()
-                .map(|x| vec_val.push(x.try_clone().unwrap().try_into().unwrap()))
-                .count();
+    for theme in themes {
+        for dir in &data_dirs {
+            for extension in ["oga", "ogg", "wav"] {
+                let sound_path = format!(
+                    "{}/sounds/{}/stereo/{}.{}",
+                    dir, theme, sound_name, extension
+                );
+                if Path::new(&sound_path).exists() {
+                    log!("Found sound: {}", sound_path);
+                    return Some(sound_path);
+                }
+            }
         }
-        _ => {
-            return None;
+    }
+    log!("No sound found");
+    None
+}
+
+/// Plays a sound file with the configured player command, replacing `{file}` with the path
+pub fn play_sound(sound_path: &str, config: &Config) {
+    if config.sound_player.is_empty() {
+        return;
+    }
+    let sound_path = match shlex::try_quote(sound_path) {
+        Ok(sound_path) => sound_path,
+        Err(_) => return,
+    };
+    let cmd = config.sound_player.replace("{file}", &sound_path);
+    log!("Playing sound: {}", cmd);
+    match tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(&cmd)
+        .spawn()
+    {
+        Ok(mut child) => {
+            tokio::spawn(async move {
+                let _ = child.wait().await;
+            });
         }
+        Err(e) => log!("Failed to play sound: {}", e),
     }
-    let res = image::save_buffer(
-        &icon_path,
-        &vec_val,
-        width as u32,
-        height as u32,
-        image::ColorType::Rgba8,
-    );
-    if res.is_err() {
+}
+
+/// Where images sent as image-data are saved for eww to display them. Uses
+/// `$XDG_CACHE_HOME/end-rs/images`, falling back to `$XDG_RUNTIME_DIR` without a home directory.
+pub fn image_cache_dir() -> PathBuf {
+    let base = env::var("XDG_CACHE_HOME")
+        .ok()
+        .filter(|dir| !dir.is_empty())
+        .or_else(|| env::var("HOME").ok().map(|home| format!("{}/.cache", home)))
+        .or_else(|| env::var("XDG_RUNTIME_DIR").ok())
+        .unwrap_or_else(|| String::from("/tmp"));
+    Path::new(&base).join("end-rs").join("images")
+}
+
+/// How long a cached image is kept without being referenced. `notify` saves images before the
+/// notification is stored, so a prune running in between would otherwise delete them.
+const IMAGE_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Deletes the cached images that aren't in `referenced`. If the rest is still larger than
+/// `max_size` bytes, the least recently used images are deleted too. A `max_size` of 0 means
+/// no limit.
+pub fn prune_image_cache(referenced: &HashSet<String>, max_size: u64) {
+    let entries = match fs::read_dir(image_cache_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let mut kept = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|extension| extension != "png") {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let used = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        let recent = used
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed < IMAGE_GRACE_PERIOD);
+        if !recent && !path.to_str().is_some_and(|path| referenced.contains(path)) {
+            log!("Removing unreferenced image {:?}", path);
+            if let Err(e) = fs::remove_file(&path) {
+                log!("Failed to remove {:?}: {}", path, e);
+            }
+            continue;
+        }
+        kept.push((used, metadata.len(), path));
+    }
+
+    let mut size: u64 = kept.iter().map(|(_, len, _)| len).sum();
+    if max_size == 0 || size <= max_size {
+        return;
+    }
+    kept.sort();
+    for (_, len, path) in kept {
+        if size <= max_size {
+            break;
+        }
+        log!("Evicting image {:?} from the cache", path);
+        if fs::remove_file(&path).is_ok() {
+            size -= len;
+        }
+    }
+}
+
+/// Decodes an image-data hint `(iiibiiay)` into tightly packed RGBA8 pixels. Returns None if the
+/// structure is malformed or the buffer doesn't match the dimensions.
+fn decode_image_data(icon_data: &Structure) -> Option<(u32, u32, Vec<u8>)> {
+    let fields = icon_data.fields();
+    if fields.len() != 7 {
+        log!("Invalid image data with {} fields", fields.len());
+        return None;
+    }
+    let int = |index: usize| -> Option<usize> {
+        match &fields[index] {
+            Value::I32(value) => usize::try_from(*value).ok(),
+            _ => None,
+        }
+    };
+    let width = int(0)?;
+    let height = int(1)?;
+    let rowstride = int(2)?;
+    let has_alpha = match &fields[3] {
+        Value::Bool(has_alpha) => *has_alpha,
+        _ => return None,
+    };
+    let bits_per_sample = int(4)?;
+    let channels = int(5)?;
+    let data: Vec<u8> = match &fields[6] {
+        Value::Array(data) => data
+            .iter()
+            .map(|byte| match byte {
+                Value::U8(byte) => Some(*byte),
+                _ => None,
+            })
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+
+    if width == 0 || height == 0 || bits_per_sample != 8 {
+        log!(
+            "Unsupported image data: {}x{}, {} bits per sample",
+            width,
+            height,
+            bits_per_sample
+        );
+        return None;
+    }
+    if channels != if has_alpha { 4 } else { 3 } {
+        log!(
+            "Image data has {} channels but has_alpha is {}",
+            channels,
+            has_alpha
+        );
+        return None;
+    }
+    let row_len = width * channels;
+    // The last row doesn't need to be padded up to the rowstride
+    if rowstride < row_len || data.len() < rowstride * (height - 1) + row_len {
+        log!(
+            "Image data of {} bytes doesn't fit {}x{} with rowstride {}",
+            data.len(),
+            width,
+            height,
+            rowstride
+        );
+        return None;
+    }
+
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for row in 0..height {
+        let row = &data[row * rowstride..row * rowstride + row_len];
+        if has_alpha {
+            pixels.extend_from_slice(row);
+        } else {
+            for pixel in row.chunks_exact(3) {
+                pixels.extend_from_slice(pixel);
+                pixels.push(u8::MAX);
+            }
+        }
+    }
+    Some((width as u32, height as u32, pixels))
+}
+
+/// 64-bit FNV-1a. Unlike `DefaultHasher` its output is stable across Rust releases, so cached
+/// images keep their names after an upgrade.
+fn fnv1a(bytes: &[u8], mut hash: u64) -> u64 {
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Saves an image-data hint as a PNG in the image cache, named after its content so that
+/// identical images are only stored once
+pub fn save_icon(icon_data: &Structure) -> Option<String> {
+    let parent_dir = image_cache_dir();
+    if let Err(e) = fs::create_dir_all(&parent_dir) {
+        log!("Failed to create {:?}: {}", parent_dir, e);
+        return None;
+    }
+    let (width, height, pixels) = decode_image_data(icon_data)?;
+    let mut hash = 0xcbf2_9ce4_8422_2325;
+    hash = fnv1a(&width.to_le_bytes(), hash);
+    hash = fnv1a(&height.to_le_bytes(), hash);
+    hash = fnv1a(&pixels, hash);
+    let icon_path = parent_dir
+        .join(format!("{:016x}.png", hash))
+        .to_str()?
+        .to_string();
+    if let Ok(file) = fs::File::options().append(true).open(&icon_path) {
+        // Already cached, mark it as recently used
+        let _ = file.set_modified(SystemTime::now());
+        return Some(icon_path);
+    }
+    let res = image::save_buffer(&icon_path, &pixels, width, height, image::ColorType::Rgba8);
+    if let Err(e) = res {
+        log!("Failed to save image to {}: {}", icon_path, e);
         return None;
     }
     Some(icon_path)
 }
 
+pub fn load_history(config: &Config) -> Vec<HistoryNotification> {
+    let history_path = config.history_path();
+    let history_str = match fs::read_to_string(&history_path) {
+        Ok(history_str) => history_str,
+        Err(_) => {
+            log!("No history found at {}", history_path);
+            return Vec::new();
+        }
+    };
+    let mut history: Vec<HistoryNotification> = history_str
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(
+            |line| match serde_json::from_str::<HistoryNotification>(line) {
+                Ok(mut entry) => {
+                    // Entries written before body markup was supported only have the raw body
+                    if entry.body_markup.is_empty() {
+                        let body = parse_body(&entry.body);
+                        entry.body = body.plain;
+                        entry.body_markup = body.markup;
+                    }
+                    Some(entry)
+                }
+                Err(e) => {
+                    log!("Skipping invalid history entry: {}", e);
+                    None
+                }
+            },
+        )
+        .collect();
+    // Entries are appended without trimming the file, only keep the newest ones
+    let excess = history
+        .len()
+        .saturating_sub(config.max_notifications as usize);
+    history.drain(..excess);
+    history
+}
+
+/// Appends a single entry to the history file, cheaper than rewriting it for every notification
+pub fn append_history(config: &Config, entry: &HistoryNotification) {
+    let history_path = config.history_path();
+    if let Some(parent_dir) = Path::new(&history_path).parent() {
+        if let Err(e) = fs::create_dir_all(parent_dir) {
+            log!("Failed to create {:?}: {}", parent_dir, e);
+            return;
+        }
+    }
+    let line = match serde_json::to_string(entry) {
+        Ok(line) => line + "\n",
+        Err(e) => {
+            log!("Failed to serialize history entry: {}", e);
+            return;
+        }
+    };
+    let written = fs::File::options()
+        .create(true)
+        .append(true)
+        .open(&history_path)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+    if let Err(e) = written {
+        log!("Failed to append to history at {}: {}", history_path, e);
+    }
+}
+
+pub fn save_history(config: &Config, history: &[HistoryNotification]) {
+    let history_path = config.history_path();
+    if let Some(parent_dir) = Path::new(&history_path).parent() {
+        if let Err(e) = fs::create_dir_all(parent_dir) {
+            log!("Failed to create {:?}: {}", parent_dir, e);
+            return;
+        }
+    }
+    let mut history_str = String::new();
+    for entry in history {
+        match serde_json::to_string(entry) {
+            Ok(line) => {
+                history_str.push_str(&line);
+                history_str.push('\n');
+            }
+            Err(e) => log!("Failed to serialize history entry: {}", e),
+        }
+    }
+    // Write to a temporary file first, renaming it over the history keeps the old file intact
+    // if the daemon dies midway
+    let tmp_path = format!("{}.tmp", history_path);
+    let written = fs::File::create(&tmp_path).and_then(|mut file| {
+        file.write_all(history_str.as_bytes())?;
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        log!("Failed to write history to {}: {}", tmp_path, e);
+        return;
+    }
+    if let Err(e) = fs::rename(&tmp_path, &history_path) {
+        log!("Failed to move history to {}: {}", history_path, e);
+    }
+}
+
 pub fn log(args: std::fmt::Arguments) {
     let message = format!(
         "[{}] {}",
@@ -143,3 +525,67 @@ macro_rules! log {
         $crate::utils::log(format_args!($($arg)*))
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_data(
+        width: i32,
+        height: i32,
+        rowstride: i32,
+        has_alpha: bool,
+        channels: i32,
+        data: Vec<u8>,
+    ) -> Structure<'static> {
+        match Value::from((width, height, rowstride, has_alpha, 8, channels, data)) {
+            Value::Structure(structure) => structure,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn decodes_rgba() {
+        let data = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let decoded = decode_image_data(&image_data(2, 1, 8, true, 4, data.clone()));
+        assert_eq!(decoded, Some((2, 1, data)));
+    }
+
+    #[test]
+    fn decodes_rgb_as_opaque_rgba() {
+        let decoded = decode_image_data(&image_data(2, 1, 6, false, 3, vec![1, 2, 3, 4, 5, 6]));
+        assert_eq!(decoded, Some((2, 1, vec![1, 2, 3, 255, 4, 5, 6, 255])));
+    }
+
+    #[test]
+    fn skips_row_padding() {
+        // Rows of 3 bytes padded to 4, without padding after the last row
+        let data = vec![1, 2, 3, 0, 4, 5, 6];
+        let decoded = decode_image_data(&image_data(1, 2, 4, false, 3, data));
+        assert_eq!(decoded, Some((1, 2, vec![1, 2, 3, 255, 4, 5, 6, 255])));
+    }
+
+    #[test]
+    fn rejects_short_buffer() {
+        assert_eq!(
+            decode_image_data(&image_data(2, 2, 8, true, 4, vec![0; 15])),
+            None
+        );
+        assert_eq!(
+            decode_image_data(&image_data(2, 1, 4, true, 4, vec![0; 8])),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_channels_not_matching_alpha() {
+        assert_eq!(
+            decode_image_data(&image_data(1, 1, 4, false, 4, vec![0; 4])),
+            None
+        );
+        assert_eq!(
+            decode_image_data(&image_data(1, 1, 3, true, 3, vec![0; 3])),
+            None
+        );
+    }
+}